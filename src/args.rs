@@ -0,0 +1,406 @@
+use anyhow::{bail, Result};
+use std::ffi::OsString;
+
+/// The arguments forwarded to Cargo, along with the flags among them that
+/// `cargo wasi` itself needs to know about.
+pub struct CargoArgs {
+    /// Everything to forward to Cargo, minus `--message-format` which is
+    /// merged with our own requirements by `MessageFormat::cargo_arg`.
+    pub forwarded: Vec<OsString>,
+    /// The target requested via `--target`, if any.
+    pub target: Option<String>,
+    pub message_format: MessageFormat,
+    pub verbose: bool,
+    /// The parallelism requested via `-j` or `--jobs`, if any.
+    pub jobs: Option<usize>,
+    /// The profile requested via `--profile`, if any.
+    pub profile: Option<String>,
+    /// Whether `--release` (or `-r`) was passed.
+    pub release: bool,
+    /// Whether `--isolate-tests` was passed, which is our own flag and isn't
+    /// forwarded.
+    pub isolate_tests: bool,
+    /// The number of test binaries to run at once via `--test-jobs`, which is
+    /// our own flag as well.
+    pub test_jobs: Option<usize>,
+    /// The revision whose benchmark results `bench` compares with via
+    /// `--compare`, which is also our own flag.
+    pub compare: Option<String>,
+    /// Whether `--no-fail-fast` was passed, so that every binary runs even if
+    /// an earlier one failed.
+    pub no_fail_fast: bool,
+}
+
+impl CargoArgs {
+    /// Parses the arguments following the subcommand, stopping at `--` since
+    /// everything after that is destined for the wasm binary.
+    pub fn parse(args: impl IntoIterator<Item = OsString>) -> Result<CargoArgs> {
+        let mut forwarded = Vec::new();
+        let mut targets = Vec::new();
+        let mut message_formats = Vec::new();
+        let mut verbose = false;
+        let mut jobs = None;
+        let mut profile = None;
+        let mut release = false;
+        let mut isolate_tests = false;
+        let mut test_jobs = None;
+        let mut compare = None;
+        let mut no_fail_fast = false;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let s = match arg.to_str() {
+                Some(s) => s,
+                None => {
+                    forwarded.push(arg);
+                    continue;
+                }
+            };
+            if s == "--" {
+                forwarded.push(arg);
+                forwarded.extend(args);
+                break;
+            }
+
+            if s.starts_with("--verbose") || s.starts_with("-v") {
+                verbose = true;
+            }
+            if s == "--isolate-tests" {
+                isolate_tests = true;
+                continue;
+            }
+            if s == "--no-fail-fast" {
+                no_fail_fast = true;
+            }
+            if s == "--release" || s == "-r" {
+                release = true;
+            }
+            if let Some(value) = flag_value(s, "--test-jobs", &mut args)? {
+                test_jobs = parse_jobs(&value, "--test-jobs")?;
+                continue;
+            }
+            if let Some(value) = flag_value(s, "--compare", &mut args)? {
+                compare = Some(value);
+                continue;
+            }
+
+            if let Some(value) = flag_value(s, "--target", &mut args)? {
+                targets.push(value.clone());
+                forwarded.push("--target".into());
+                forwarded.push(value.into());
+                continue;
+            }
+            if let Some(value) = flag_value(s, "--profile", &mut args)? {
+                profile = Some(value.clone());
+                forwarded.push("--profile".into());
+                forwarded.push(value.into());
+                continue;
+            }
+            let jobs_value = if s == "-j" {
+                flag_value("--jobs", "--jobs", &mut args)?
+            } else if let Some(n) = s.strip_prefix("-j") {
+                Some(n.to_string())
+            } else {
+                flag_value(s, "--jobs", &mut args)?
+            };
+            if let Some(value) = jobs_value {
+                jobs = parse_jobs(&value, "--jobs")?;
+                forwarded.push("--jobs".into());
+                forwarded.push(value.into());
+                continue;
+            }
+            if let Some(value) = flag_value(s, "--message-format", &mut args)? {
+                message_formats.extend(value.split(',').map(|s| s.trim().to_string()));
+                continue;
+            }
+            forwarded.push(arg);
+        }
+
+        if targets.len() > 1 {
+            bail!("`cargo wasi` only supports building for one `--target` at a time");
+        }
+        Ok(CargoArgs {
+            forwarded,
+            target: targets.pop(),
+            message_format: MessageFormat {
+                requested: message_formats,
+            },
+            verbose,
+            jobs,
+            profile,
+            release,
+            isolate_tests,
+            test_jobs,
+            compare,
+            no_fail_fast,
+        })
+    }
+}
+
+impl CargoArgs {
+    /// The name of the Cargo profile that the build uses, the same way Cargo
+    /// picks it: `--profile` if given, `release` for `--release`, and
+    /// otherwise `default`, which depends on the subcommand.
+    pub fn profile_name(&self, default: &str) -> String {
+        match &self.profile {
+            Some(profile) => profile.clone(),
+            None if self.release => "release".to_string(),
+            None => default.to_string(),
+        }
+    }
+}
+
+/// Interprets a `--jobs` value the same way Cargo does, where negative
+/// numbers are relative to the number of CPUs and `default` means no limit
+/// was requested.
+fn parse_jobs(value: &str, flag: &str) -> Result<Option<usize>> {
+    if value == "default" {
+        return Ok(None);
+    }
+    let jobs = match value.parse::<i64>() {
+        Ok(0) | Err(_) => bail!("invalid value for `{}`: `{}`", flag, value),
+        Ok(n) => n,
+    };
+    if jobs > 0 {
+        return Ok(Some(jobs as usize));
+    }
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get()) as i64;
+    Ok(Some((cpus + jobs).max(1) as usize))
+}
+
+/// The options of one of our own subcommands as pairs of the flag and its
+/// value, if it takes one.
+pub type OwnOptions = Vec<(&'static str, Option<OsString>)>;
+
+/// Splits the options of our own subcommands, such as `--debugger` of
+/// `debug`, out of `args`, returning them in order along with the rest of the
+/// arguments to pass on.
+///
+/// `switches` are flags on their own, while `options` take a value as either
+/// `--flag value` or `--flag=value`. Everything after a literal `--` is passed
+/// on, as are arguments which aren't UTF-8 and so can't be one of ours.
+pub fn split_options(
+    args: Vec<OsString>,
+    switches: &[&'static str],
+    options: &[&'static str],
+) -> Result<(OwnOptions, Vec<OsString>)> {
+    let mut own = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let s = match arg.to_str() {
+            Some("--") => {
+                rest.push(arg);
+                rest.extend(args.by_ref());
+                break;
+            }
+            Some(s) => s,
+            None => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        if let Some(switch) = switches.iter().find(|switch| **switch == s) {
+            own.push((*switch, None));
+            continue;
+        }
+        let (flag, value) = match s.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(OsString::from(value))),
+            _ => (s, None),
+        };
+        let flag = match options.iter().find(|option| **option == flag) {
+            Some(flag) => *flag,
+            None => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        match value.or_else(|| args.next()) {
+            Some(value) => own.push((flag, Some(value))),
+            None => bail!("`{}` must be followed by a value", flag),
+        }
+    }
+    Ok((own, rest))
+}
+
+/// Returns the value of `flag` if `arg` is that flag, either in the form
+/// `--flag=value` or as `--flag value`.
+fn flag_value(
+    arg: &str,
+    flag: &str,
+    rest: &mut impl Iterator<Item = OsString>,
+) -> Result<Option<String>> {
+    if arg == flag {
+        return match rest.next().and_then(|s| s.into_string().ok()) {
+            Some(value) => Ok(Some(value)),
+            None => bail!("`{}` must be followed by a value", flag),
+        };
+    }
+    match arg.strip_prefix(flag).and_then(|s| s.strip_prefix('=')) {
+        Some(value) => Ok(Some(value.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// The `--message-format` the user asked Cargo for.
+///
+/// We always need JSON from Cargo to learn about artifacts, so this tracks
+/// whether the user also wants to see that JSON on stdout, or whether they
+/// just want diagnostics rendered like normal.
+pub struct MessageFormat {
+    requested: Vec<String>,
+}
+
+impl MessageFormat {
+    /// Whether the user requested a JSON message stream on stdout.
+    pub fn is_json(&self) -> bool {
+        self.requested.iter().any(|f| f.starts_with("json"))
+    }
+
+    /// The `--message-format` that the user passed, if any, for subcommands
+    /// whose output we leave to Cargo.
+    pub fn requested(&self) -> Option<String> {
+        if self.requested.is_empty() {
+            return None;
+        }
+        Some(self.requested.join(","))
+    }
+
+    /// The value to pass as `--message-format` to Cargo.
+    pub fn cargo_arg(&self) -> String {
+        if self.is_json() {
+            return self.requested.join(",");
+        }
+        // Cargo renders diagnostics itself with `json-render-diagnostics`,
+        // using rustc's short rendering if that's what was asked for.
+        let mut formats = vec!["json-render-diagnostics"];
+        if self.requested.iter().any(|f| f == "short") {
+            formats.push("json-diagnostic-short");
+        }
+        formats.join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> CargoArgs {
+        CargoArgs::parse(args.iter().map(OsString::from)).unwrap()
+    }
+
+    fn forwarded(args: &CargoArgs) -> Vec<&str> {
+        args.forwarded.iter().map(|s| s.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn flag_forms() {
+        for args in [
+            &["--target", "wasm32-wasip2", "--profile", "bench"][..],
+            &["--target=wasm32-wasip2", "--profile=bench"][..],
+        ]
+        .iter()
+        {
+            let args = parse(args);
+            assert_eq!(args.target.as_deref(), Some("wasm32-wasip2"));
+            assert_eq!(args.profile.as_deref(), Some("bench"));
+            assert_eq!(
+                forwarded(&args),
+                ["--target", "wasm32-wasip2", "--profile", "bench"]
+            );
+        }
+    }
+
+    #[test]
+    fn missing_value() {
+        let err = CargoArgs::parse(vec![OsString::from("--target")])
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "`--target` must be followed by a value");
+    }
+
+    #[test]
+    fn jobs() {
+        for args in [&["-j", "3"][..], &["-j3"], &["--jobs", "3"], &["--jobs=3"]].iter() {
+            let args = parse(args);
+            assert_eq!(args.jobs, Some(3));
+            assert_eq!(forwarded(&args), ["--jobs", "3"]);
+        }
+
+        let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let args = parse(&["-j-1"]);
+        assert_eq!(args.jobs, Some(cpus.saturating_sub(1).max(1)));
+        assert_eq!(forwarded(&args), ["--jobs", "-1"]);
+        assert_eq!(parse(&["-j", "-1000000"]).jobs, Some(1));
+        assert_eq!(parse(&["--jobs=default"]).jobs, None);
+
+        for value in ["0", "x", ""].iter() {
+            let err = CargoArgs::parse(vec![OsString::from(format!("--jobs={}", value))])
+                .err()
+                .unwrap();
+            assert_eq!(
+                err.to_string(),
+                format!("invalid value for `--jobs`: `{}`", value)
+            );
+        }
+    }
+
+    #[test]
+    fn own_flags_not_forwarded() {
+        let args = parse(&[
+            "--isolate-tests",
+            "--test-jobs=2",
+            "--compare",
+            "main",
+            "--no-fail-fast",
+            "--release",
+        ]);
+        assert!(args.isolate_tests);
+        assert_eq!(args.test_jobs, Some(2));
+        assert_eq!(args.compare.as_deref(), Some("main"));
+        assert!(args.no_fail_fast);
+        assert!(args.release);
+        assert_eq!(forwarded(&args), ["--no-fail-fast", "--release"]);
+    }
+
+    #[test]
+    fn stops_at_separator() {
+        let args = parse(&["--lib", "--", "--target", "foo", "--isolate-tests"]);
+        assert_eq!(args.target, None);
+        assert!(!args.isolate_tests);
+        assert_eq!(
+            forwarded(&args),
+            ["--lib", "--", "--target", "foo", "--isolate-tests"]
+        );
+    }
+
+    #[test]
+    fn one_target() {
+        let err = CargoArgs::parse(["--target", "a", "--target=b"].iter().map(OsString::from))
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "`cargo wasi` only supports building for one `--target` at a time"
+        );
+    }
+
+    #[test]
+    fn message_format() {
+        let args = parse(&["--message-format", "short", "-v"]);
+        assert!(args.verbose);
+        assert_eq!(forwarded(&args), ["-v"]);
+        assert!(!args.message_format.is_json());
+        assert_eq!(
+            args.message_format.cargo_arg(),
+            "json-render-diagnostics,json-diagnostic-short"
+        );
+
+        let args = parse(&["--message-format=json-diagnostic-short, json-render-diagnostics"]);
+        assert!(args.message_format.is_json());
+        assert_eq!(
+            args.message_format.cargo_arg(),
+            "json-diagnostic-short,json-render-diagnostics"
+        );
+    }
+}
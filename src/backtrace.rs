@@ -0,0 +1,561 @@
+use crate::utils::read_uleb128;
+use anyhow::{bail, Context, Result};
+use gimli::{EndianRcSlice, RunTimeEndian};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::rc::Rc;
+use walrus::{Module, RawCustomSection};
+
+type Reader = EndianRcSlice<RunTimeEndian>;
+
+/// Whether the user asked for backtraces, which are then symbolicated even
+/// when nothing else needs the runtime's stderr to be filtered.
+pub fn requested() -> bool {
+    ["RUST_BACKTRACE", "WASMTIME_BACKTRACE_DETAILS"]
+        .iter()
+        .any(|var| env::var_os(var).is_some_and(|value| value != "0"))
+}
+
+/// Copies the stderr of a runtime executing `wasm` to `out`, rewriting the
+/// frames of any wasm backtrace along the way.
+///
+/// Runtimes print frames as offsets into the module, with names straight from
+/// the name section if at all. Each frame is rewritten with a readable
+/// function name and, if the `*.rustc.wasm` that `wasm` was post-processed from
+/// has DWARF debuginfo, the file and line of the offset. The module is only loaded once a frame is seen, and if that fails
+/// then the output is left as is.
+///
+/// Everything else is copied byte for byte as soon as it's read, so partial
+/// lines such as prompts aren't held back, and only complete lines are
+/// considered as frames.
+///
+/// Returns the message and rewritten backtrace of a trap, if there was one.
+pub fn filter(
+    mut stderr: impl Read,
+    mut out: impl Write,
+    wasm: &Path,
+) -> io::Result<Option<String>> {
+    let mut filter = Filter {
+        wasm,
+        symbols: None,
+        skip_locations: false,
+        trap: String::new(),
+    };
+    // The line being read, the first `written` bytes of which were already
+    // copied before the rest of it arrived.
+    let mut line = Vec::new();
+    let mut written = 0;
+    let mut buf = [0; 8192];
+    loop {
+        let n = match stderr.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let mut chunk = &buf[..n];
+        while let Some(i) = chunk.iter().position(|&b| b == b'\n') {
+            line.extend_from_slice(&chunk[..=i]);
+            chunk = &chunk[i + 1..];
+            filter.line(&line, written, &mut out)?;
+            line.clear();
+            written = 0;
+        }
+        line.extend_from_slice(chunk);
+        out.write_all(&line[written..])?;
+        written = line.len();
+    }
+    if !line.is_empty() {
+        filter.line(&line, written, &mut out)?;
+    }
+    let trap = filter.trap;
+    Ok(if trap.is_empty() { None } else { Some(trap) })
+}
+
+struct Filter<'a> {
+    wasm: &'a Path,
+    symbols: Option<Option<Symbols>>,
+    skip_locations: bool,
+    trap: String,
+}
+
+impl Filter<'_> {
+    /// Copies `line` to `out` apart from its first `written` bytes, rewriting
+    /// it instead if it's a frame of which nothing was written yet.
+    fn line(&mut self, line: &[u8], written: usize, mut out: impl Write) -> io::Result<()> {
+        let end = line.strip_suffix(b"\n").unwrap_or(line);
+        let end = end.strip_suffix(b"\r").unwrap_or(end);
+        let text = std::str::from_utf8(end).ok();
+        let frame = text.and_then(Frame::parse);
+        let rewritten = match &frame {
+            Some(frame) if written == 0 => {
+                let wasm = self.wasm;
+                let symbols = self.symbols.get_or_insert_with(|| Symbols::load(wasm).ok());
+                symbols.as_ref().and_then(|symbols| frame.rewrite(symbols))
+            }
+            _ => None,
+        };
+        if let Some(rewritten) = rewritten {
+            out.write_all(rewritten.as_bytes())?;
+            self.trap.push_str(&rewritten);
+            // We print locations ourselves, so skip any that `wasmtime`
+            // printed below the frame we've just rewritten.
+            self.skip_locations = matches!(frame, Some(Frame::Wasmtime { .. }));
+            return Ok(());
+        }
+        let is_location = text.is_some_and(|text| text.trim_start().starts_with("at "));
+        if self.skip_locations && is_location && written == 0 {
+            return Ok(());
+        }
+        out.write_all(&line[written..])?;
+        let text = String::from_utf8_lossy(end);
+        if frame.is_some()
+            || text.contains("wasm trap")
+            || text.contains("forcing trap")
+            || text.contains("RuntimeError")
+        {
+            self.trap.push_str(&text);
+            self.trap.push('\n');
+        }
+        self.skip_locations = false;
+        Ok(())
+    }
+}
+
+/// A frame of a backtrace as printed by one of the runtimes.
+enum Frame<'a> {
+    /// `  3: 0x1234 - foo!bar` from `wasmtime`, where `prefix` is up to the
+    /// offset and `index` up to the colon.
+    Wasmtime {
+        prefix: &'a str,
+        index: usize,
+        offset: usize,
+    },
+    /// `    at bar (wasm://wasm/foo:wasm-function[3]:0x1234)` from Node.js and
+    /// other V8-based runtimes, also used by `wasmer`.
+    At {
+        indent: &'a str,
+        location: &'a str,
+        offset: usize,
+    },
+    /// `wasm trap: unreachable, source location: @1234` from older versions of
+    /// `wasmtime`, where `end` is the position after the offset.
+    SourceLocation {
+        line: &'a str,
+        end: usize,
+        offset: usize,
+    },
+}
+
+impl<'a> Frame<'a> {
+    fn parse(line: &'a str) -> Option<Frame<'a>> {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+
+        if let Some((index, rest)) = trimmed.split_once(':') {
+            if !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()) {
+                let rest = rest.trim_start().strip_prefix("0x")?;
+                let (hex, rest) = split_hex(rest)?;
+                if !rest.starts_with(" - ") {
+                    return None;
+                }
+                return Some(Frame::Wasmtime {
+                    prefix: &line[..line.len() - rest.len() - hex.len() - 2],
+                    index: indent.len() + index.len() + 1,
+                    offset: usize::from_str_radix(hex, 16).ok()?,
+                });
+            }
+        }
+
+        if let Some(rest) = trimmed.strip_prefix("at ") {
+            let location = match rest.rfind(" (") {
+                Some(i) if rest.ends_with(')') => &rest[i + 2..rest.len() - 1],
+                _ => rest,
+            };
+            let (_, hex) = location.rsplit_once(":0x")?;
+            let (hex, tail) = split_hex(hex)?;
+            if !tail.is_empty() {
+                return None;
+            }
+            return Some(Frame::At {
+                indent,
+                location,
+                offset: usize::from_str_radix(hex, 16).ok()?,
+            });
+        }
+
+        let start = line.find("source location: @")? + "source location: @".len();
+        let (hex, _) = split_hex(&line[start..])?;
+        Some(Frame::SourceLocation {
+            line,
+            end: start + hex.len(),
+            offset: usize::from_str_radix(hex, 16).ok()?,
+        })
+    }
+
+    /// Returns the lines replacing this frame, or `None` if `symbols` don't
+    /// know about its offset.
+    fn rewrite(&self, symbols: &Symbols) -> Option<String> {
+        match *self {
+            Frame::Wasmtime {
+                prefix,
+                index,
+                offset,
+            } => {
+                let name = symbols.function(offset)?;
+                let mut lines = format!("{}0x{:x} - {}\n", prefix, offset, name);
+                if let Some(location) = symbols.location(offset) {
+                    let indent = " ".repeat(index + 4);
+                    lines.push_str(&format!("{}at {}\n", indent, location));
+                }
+                Some(lines)
+            }
+            Frame::At {
+                indent,
+                location,
+                offset,
+            } => {
+                let name = symbols.function(offset)?;
+                let location = symbols
+                    .location(offset)
+                    .unwrap_or_else(|| location.to_string());
+                Some(format!("{}at {} ({})\n", indent, name, location))
+            }
+            Frame::SourceLocation { line, end, offset } => {
+                let name = symbols.function(offset)?;
+                let location = match symbols.location(offset) {
+                    Some(location) => format!("{} at {}", name, location),
+                    None => name,
+                };
+                Some(format!("{} ({}){}\n", &line[..end], location, &line[end..]))
+            }
+        }
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn split_hex(s: &str) -> Option<(&str, &str)> {
+    let len = s.bytes().take_while(|b| b.is_ascii_hexdigit()).count();
+    if len == 0 {
+        return None;
+    }
+    Some(s.split_at(len))
+}
+
+/// The information needed to symbolicate offsets into a module.
+struct Symbols {
+    bytes: Vec<u8>,
+    /// The defined functions, in the order of their bodies.
+    funcs: Vec<Func>,
+    original: Option<Original>,
+}
+
+struct Func {
+    index: usize,
+    /// The byte range of the function's body.
+    body: Range<usize>,
+    /// The name from the name section, which `process_wasm` demangled.
+    name: Option<String>,
+}
+
+/// The module as rustc produced it, which is kept at `*.rustc.wasm`.
+///
+/// `walrus` re-encodes and reorders the code that it emits without updating
+/// the DWARF to match, so the DWARF is only valid for the code of this module.
+struct Original {
+    bytes: Vec<u8>,
+    /// The bodies of the functions by their demangled names, like in the
+    /// module that was post-processed.
+    bodies: HashMap<String, Range<usize>>,
+    /// Offset of the contents of the code section, which DWARF addresses are
+    /// relative to.
+    code_start: usize,
+    dwarf: addr2line::Context<Reader>,
+}
+
+impl Symbols {
+    fn load(wasm: &Path) -> Result<Symbols> {
+        let bytes = fs::read(wasm)?;
+        let funcs = functions(&bytes)?;
+        let original = match fs::read(wasm.with_extension("rustc.wasm")) {
+            Ok(bytes) => Original::load(bytes)?,
+            Err(_) => None,
+        };
+        Ok(Symbols {
+            bytes,
+            funcs,
+            original,
+        })
+    }
+
+    /// The name of the function containing `offset`.
+    fn function(&self, offset: usize) -> Option<String> {
+        let func = self.func(offset)?;
+        Some(match &func.name {
+            Some(name) => readable_name(name),
+            None => format!("<wasm function {}>", func.index),
+        })
+    }
+
+    fn func(&self, offset: usize) -> Option<&Func> {
+        let i = self.funcs.partition_point(|f| f.body.end <= offset);
+        self.funcs.get(i).filter(|f| f.body.contains(&offset))
+    }
+
+    /// The source location of `offset` as `file:line:column`, if it's known.
+    fn location(&self, offset: usize) -> Option<String> {
+        let func = self.func(offset)?;
+        let original = self.original.as_ref()?;
+        let original_body = original.bodies.get(func.name.as_ref()?)?;
+        // Instructions are emitted one for one, so the offset corresponds to
+        // that of the same instruction in the original function.
+        let ours = instructions(&self.bytes, &func.body)?;
+        let theirs = instructions(&original.bytes, original_body)?;
+        if ours.len() != theirs.len() {
+            return None;
+        }
+        let n = ours.partition_point(|&o| o <= offset).checked_sub(1)?;
+        let address = (theirs[n] - original.code_start) as u64;
+
+        let location = original.dwarf.find_location(address).ok()??;
+        let mut s = location.file?.to_string();
+        if let Some(line) = location.line {
+            s.push_str(&format!(":{}", line));
+            if let Some(column) = location.column {
+                s.push_str(&format!(":{}", column));
+            }
+        }
+        Some(s)
+    }
+}
+
+impl Original {
+    /// Loads the module `bytes`, or returns `None` if it has no DWARF.
+    fn load(bytes: Vec<u8>) -> Result<Option<Original>> {
+        let dwarf = match load_dwarf(&Module::from_buffer(&bytes)?)? {
+            Some(dwarf) => dwarf,
+            None => return Ok(None),
+        };
+        let (code_start, _) = code_section(&bytes)?;
+        // Functions whose names aren't unique can't be told apart.
+        let mut bodies = HashMap::new();
+        let mut duplicates = Vec::new();
+        for func in functions(&bytes)? {
+            let name = match func.name {
+                Some(name) => match rustc_demangle::try_demangle(&name) {
+                    Ok(sym) => sym.to_string(),
+                    Err(_) => name,
+                },
+                None => continue,
+            };
+            if bodies.insert(name.clone(), func.body).is_some() {
+                duplicates.push(name);
+            }
+        }
+        for name in duplicates {
+            bodies.remove(&name);
+        }
+        Ok(Some(Original {
+            bytes,
+            bodies,
+            code_start,
+            dwarf,
+        }))
+    }
+}
+
+/// Returns the defined functions of the module `bytes`, in order.
+fn functions(bytes: &[u8]) -> Result<Vec<Func>> {
+    let module = Module::from_buffer(bytes)?;
+    let (_, bodies) = code_section(bytes)?;
+    let imported = module.funcs.iter().count() - bodies.len();
+    Ok(bodies
+        .into_iter()
+        .zip(module.funcs.iter().skip(imported))
+        .enumerate()
+        .map(|(i, (body, func))| Func {
+            index: imported + i,
+            body,
+            name: func.name.clone(),
+        })
+        .collect())
+}
+
+/// Returns the offsets of the instructions of the function `body` in the
+/// module `bytes`.
+fn instructions(bytes: &[u8], body: &Range<usize>) -> Option<Vec<usize>> {
+    let body = wasmparser::FunctionBody::new(body.start, bytes.get(body.clone())?);
+    let mut reader = body.get_operators_reader().ok()?;
+    let mut offsets = Vec::new();
+    while !reader.eof() {
+        offsets.push(reader.read_with_offset().ok()?.1);
+    }
+    Some(offsets)
+}
+
+fn custom_section<'a>(module: &'a Module, name: &str) -> Option<&'a [u8]> {
+    let (_, section) = module.customs.iter().find(|(_, s)| s.name() == name)?;
+    let section = section.as_any().downcast_ref::<RawCustomSection>()?;
+    Some(&section.data)
+}
+
+fn load_dwarf(module: &Module) -> Result<Option<addr2line::Context<Reader>>> {
+    if custom_section(module, ".debug_info").is_none() {
+        return Ok(None);
+    }
+    let dwarf = gimli::Dwarf::load(|id| -> Result<Reader> {
+        let data = custom_section(module, id.name()).unwrap_or_default();
+        Ok(EndianRcSlice::new(Rc::from(data), RunTimeEndian::Little))
+    })?;
+    Ok(Some(addr2line::Context::from_dwarf(dwarf)?))
+}
+
+/// Finds the offset of the contents of the code section in the module
+/// `bytes`, along with the byte ranges of each function body.
+fn code_section(bytes: &[u8]) -> Result<(usize, Vec<Range<usize>>)> {
+    let mut data = bytes.get(8..).unwrap_or_default();
+    while let Some((&id, mut rest)) = data.split_first() {
+        let len = read_uleb128(&mut rest).context("invalid section size")? as usize;
+        if rest.len() < len {
+            bail!("section extends past the end of the module");
+        }
+        if id != 10 {
+            data = &rest[len..];
+            continue;
+        }
+
+        let start = bytes.len() - rest.len();
+        let mut contents = &rest[..len];
+        let count = read_uleb128(&mut contents).context("invalid function count")?;
+        let mut bodies = Vec::new();
+        for _ in 0..count {
+            let size = read_uleb128(&mut contents).context("invalid function size")? as usize;
+            let body = bytes.len() - rest.len() + (len - contents.len());
+            if contents.len() < size {
+                bail!("function extends past the end of the code section");
+            }
+            contents = &contents[size..];
+            bodies.push(body..body + size);
+        }
+        return Ok((start, bodies));
+    }
+    Ok((0, Vec::new()))
+}
+
+/// Names were demangled by `process_wasm`, but keep the hashes which only get
+/// in the way of reading a backtrace, so trim those off.
+fn readable_name(name: &str) -> String {
+    if let Ok(sym) = rustc_demangle::try_demangle(name) {
+        return format!("{:#}", sym);
+    }
+
+    // Legacy symbols end in `::h0123456789abcdef`.
+    let mut name = match name.rfind("::h") {
+        Some(i) if name.len() - i == 19 && is_hex(&name[i + 3..]) => name[..i].to_string(),
+        _ => name.to_string(),
+    };
+
+    // v0 symbols have crate disambiguators like `std[0123456789abcdef]::`.
+    let mut start = 0;
+    while let Some(i) = name[start..].find('[').map(|i| start + i) {
+        let rest = &name[i + 1..];
+        match split_hex(rest) {
+            Some((hex, tail)) if tail.starts_with("]::") && i > 0 => {
+                let len = hex.len();
+                name.replace_range(i..i + len + 2, "");
+            }
+            _ => {}
+        }
+        start = i + 1;
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Option<String> {
+        Some(match Frame::parse(line)? {
+            Frame::Wasmtime {
+                prefix,
+                index,
+                offset,
+            } => format!("wasmtime {:?} {:?} {:#x}", prefix, &line[..index], offset),
+            Frame::At {
+                indent,
+                location,
+                offset,
+            } => format!("at {:?} {:?} {:#x}", indent, location, offset),
+            Frame::SourceLocation { line, end, offset } => {
+                format!("source location {:?} {:#x}", &line[..end], offset)
+            }
+        })
+    }
+
+    #[test]
+    fn wasmtime() {
+        assert_eq!(
+            parse("    3: 0x1a2b - foo!bar::baz").as_deref(),
+            Some(r#"wasmtime "    3: " "    3:" 0x1a2b"#)
+        );
+        assert_eq!(
+            parse("12:0xff - <unknown>!<wasm function 4>").as_deref(),
+            Some(r#"wasmtime "12:" "12:" 0xff"#)
+        );
+    }
+
+    #[test]
+    fn at() {
+        assert_eq!(
+            parse("    at foo::bar (wasm://wasm/0012abcd:wasm-function[3]:0x1234)").as_deref(),
+            Some(r#"at "    " "wasm://wasm/0012abcd:wasm-function[3]:0x1234" 0x1234"#)
+        );
+        assert_eq!(
+            parse("  at wasm://wasm/foo:wasm-function[3]:0xABC").as_deref(),
+            Some(r#"at "  " "wasm://wasm/foo:wasm-function[3]:0xABC" 0xabc"#)
+        );
+    }
+
+    #[test]
+    fn source_location() {
+        assert_eq!(
+            parse("wasm trap: unreachable, source location: @1234 and more").as_deref(),
+            Some(r#"source location "wasm trap: unreachable, source location: @1234" 0x1234"#)
+        );
+    }
+
+    #[test]
+    fn not_frames() {
+        for line in [
+            "",
+            "hello world",
+            "3: 0x - foo",
+            "3: 0x12",
+            "3: 0x12 foo",
+            "3: 1234 - foo",
+            "x3: 0x12 - foo",
+            "at src/main.rs:11:21",
+            "at foo (wasm://wasm/foo:wasm-function[3]:0x12) extra",
+            "at foo (wasm://wasm/foo:wasm-function[3]:0x12z)",
+            "source location: @",
+            "source location: @zz",
+        ]
+        .iter()
+        {
+            assert_eq!(parse(line), None, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn overflowing_offset() {
+        assert_eq!(parse("0: 0x1ffffffffffffffffff - foo"), None);
+    }
+}
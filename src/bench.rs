@@ -0,0 +1,354 @@
+use crate::config::Config;
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Changes in time smaller than this fraction of the baseline are reported
+/// as unchanged, however small the spread of the measurements.
+const THRESHOLD: f64 = 0.05;
+
+/// The time of one benchmark, as printed by libtest or criterion.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Bench {
+    /// The name of the binary, without the hash Cargo adds to it.
+    pub binary: String,
+    pub name: String,
+    /// The time of one iteration, in nanoseconds.
+    pub ns: f64,
+    /// How far the measurements spread around `ns`, which is libtest's
+    /// `+/-` or half of criterion's confidence interval.
+    pub spread: Option<f64>,
+}
+
+/// The results of benchmarks at one revision, as stored in
+/// `target/wasi-bench/<revision>.json`.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct Results {
+    revision: String,
+    benches: Vec<Bench>,
+}
+
+/// Picks the benchmark results out of the stdout of a bench binary, which
+/// may use libtest's format (also printed by criterion with
+/// `--output-format bencher`) or criterion's own.
+pub fn parse(binary: &str, stdout: &[u8]) -> Vec<Bench> {
+    let mut benches = Vec::new();
+    // Criterion prints the `time:` on the next line after long names.
+    let mut previous: Option<&str> = None;
+    let stdout = String::from_utf8_lossy(stdout);
+    for line in stdout.lines() {
+        if let Some((name, ns, spread)) = parse_libtest(line) {
+            benches.push(Bench {
+                binary: binary.to_string(),
+                name: name.to_string(),
+                ns,
+                spread,
+            });
+        } else if let Some((name, [low, ns, high])) = parse_criterion(line) {
+            let name = match name {
+                "" => previous.unwrap_or_default(),
+                name => name,
+            };
+            benches.push(Bench {
+                binary: binary.to_string(),
+                name: name.to_string(),
+                ns,
+                spread: Some((high - low) / 2.0),
+            });
+        }
+        previous = if line.starts_with(char::is_whitespace) {
+            None
+        } else {
+            Some(line.trim())
+        };
+    }
+    benches
+}
+
+/// Parses `test <name> ... bench: <ns> ns/iter (+/- <spread>)`, where libtest
+/// pads the name to the length of the longest one.
+fn parse_libtest(line: &str) -> Option<(&str, f64, Option<f64>)> {
+    let (name, rest) = line.strip_prefix("test ")?.split_once(" ... bench:")?;
+    let (ns, rest) = rest.trim_start().split_once(" ns/iter")?;
+    let spread = rest
+        .trim()
+        .strip_prefix("(+/- ")
+        .and_then(|s| s.strip_suffix(')'))
+        .and_then(parse_number);
+    Some((name.trim_end(), parse_number(ns)?, spread))
+}
+
+/// Parses `<name> time: [<low> <unit> <estimate> <unit> <high> <unit>]`,
+/// returning the times in nanoseconds.
+fn parse_criterion(line: &str) -> Option<(&str, [f64; 3])> {
+    let (name, rest) = line.split_once("time:")?;
+    let rest = rest.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let words = rest.split_whitespace().collect::<Vec<_>>();
+    if words.len() != 6 {
+        return None;
+    }
+    let mut times = [0.0; 3];
+    for (time, pair) in times.iter_mut().zip(words.chunks(2)) {
+        let scale = match pair[1] {
+            "ps" => 0.001,
+            "ns" => 1.0,
+            "µs" | "us" => 1_000.0,
+            "ms" => 1_000_000.0,
+            "s" => 1_000_000_000.0,
+            _ => return None,
+        };
+        // Rounded to picoseconds, so that times read back from JSON are the
+        // same as the ones printed.
+        *time = (parse_number(pair[0])? * scale * 1000.0).round() / 1000.0;
+    }
+    Some((name.trim(), times))
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    s.trim().replace(',', "").parse().ok()
+}
+
+/// Where the results of `revision` are stored.
+fn path(target_directory: &Path, revision: &str) -> PathBuf {
+    target_directory
+        .join("wasi-bench")
+        .join(format!("{}.json", revision))
+}
+
+/// The git revision of the workspace, with `-dirty` appended if it has
+/// uncommitted changes, or `None` outside of a git repository.
+pub fn revision(workspace_root: &Path) -> Option<String> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(workspace_root)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let commit = git(&["rev-parse", "HEAD"])?;
+    if git(&["status", "--porcelain", "--untracked-files=no"])?.is_empty() {
+        Some(commit)
+    } else {
+        Some(format!("{}-dirty", commit))
+    }
+}
+
+/// The benchmark results to compare against, where `baseline` is anything
+/// that git resolves to a commit, such as a branch or tag, or the name of
+/// results stored for a dirty revision.
+pub struct Baseline {
+    name: String,
+    benches: Vec<Bench>,
+}
+
+impl Baseline {
+    /// Loads the results of `baseline`, before anything is built so that a
+    /// baseline without results fails quickly.
+    pub fn load(
+        baseline: &str,
+        workspace_root: &Path,
+        target_directory: &Path,
+    ) -> Result<Baseline> {
+        let commit = Command::new("git")
+            .args(["rev-parse", "--verify", "--quiet"])
+            .arg(format!("{}^{{commit}}", baseline))
+            .current_dir(workspace_root)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+        let path = path(target_directory, commit.as_deref().unwrap_or(baseline));
+        if !path.exists() {
+            bail!(
+                "no benchmark results for `{}` in `{}`, you'll want to run \
+                 `cargo wasi bench` at that revision first",
+                baseline,
+                path.display(),
+            );
+        }
+        let json = fs::read(&path).context(format!("failed to read `{}`", path.display()))?;
+        let results = serde_json::from_slice::<Results>(&json)
+            .context(format!("failed to parse `{}`", path.display()))?;
+        Ok(Baseline {
+            name: baseline.to_string(),
+            benches: results.benches,
+        })
+    }
+
+    /// Prints how each of `benches` changed since the baseline.
+    pub fn compare(&self, benches: &[Bench], config: &Config) {
+        config.status("Comparing", &format!("with `{}`", self.name));
+        let width = benches.iter().map(|b| label(b).len()).max().unwrap_or(0);
+        let (mut regressed, mut improved, mut unchanged) = (0, 0, 0);
+        for bench in benches {
+            let old = self
+                .benches
+                .iter()
+                .find(|old| old.binary == bench.binary && old.name == bench.name);
+            let old = match old {
+                Some(old) => old,
+                None => {
+                    println!(
+                        "{:<width$}  {:>12}  new",
+                        label(bench),
+                        format_time(bench.ns),
+                        width = width,
+                    );
+                    continue;
+                }
+            };
+            let delta = bench.ns - old.ns;
+            let noise = bench.spread.unwrap_or(0.0) + old.spread.unwrap_or(0.0);
+            let verdict = if delta.abs() <= old.ns * THRESHOLD || delta.abs() <= noise {
+                unchanged += 1;
+                "no change"
+            } else if delta > 0.0 {
+                regressed += 1;
+                "regressed"
+            } else {
+                improved += 1;
+                "improved"
+            };
+            println!(
+                "{:<width$}  {:>12} -> {:>12}  {:>+8.2}%  {}",
+                label(bench),
+                format_time(old.ns),
+                format_time(bench.ns),
+                delta / old.ns * 100.0,
+                verdict,
+                width = width,
+            );
+        }
+        config.status(
+            "Compared",
+            &format!(
+                "{} regressed, {} improved, {} unchanged",
+                regressed, improved, unchanged
+            ),
+        );
+    }
+}
+
+fn label(bench: &Bench) -> String {
+    format!("{}::{}", bench.binary, bench.name)
+}
+
+fn format_time(ns: f64) -> String {
+    if ns >= 1_000_000_000.0 {
+        format!("{:.2} s", ns / 1_000_000_000.0)
+    } else if ns >= 1_000_000.0 {
+        format!("{:.2} ms", ns / 1_000_000.0)
+    } else if ns >= 1_000.0 {
+        format!("{:.2} µs", ns / 1_000.0)
+    } else {
+        format!("{:.2} ns", ns)
+    }
+}
+
+/// Stores `benches` as the results of `revision`, replacing earlier results
+/// of the same benchmarks but keeping the others, so that benches run one at
+/// a time add up.
+pub fn save(
+    benches: &[Bench],
+    revision: &str,
+    target_directory: &Path,
+    config: &Config,
+) -> Result<()> {
+    let path = path(target_directory, revision);
+    let mut results = fs::read(&path)
+        .ok()
+        .and_then(|json| serde_json::from_slice::<Results>(&json).ok())
+        .unwrap_or_default();
+    results.revision = revision.to_string();
+    results.benches.retain(|old| {
+        !benches
+            .iter()
+            .any(|b| b.binary == old.binary && b.name == old.name)
+    });
+    results.benches.extend(benches.iter().cloned());
+
+    config.status("Writing", &format!("`{}`", path.display()));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context(format!("failed to create directory `{}`", parent.display()))?;
+    }
+    let json = serde_json::to_string_pretty(&results)?;
+    fs::write(&path, json).context(format!("failed to write `{}`", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    fn parse(stdout: &str) -> Vec<(String, f64, Option<f64>)> {
+        super::parse("foo", stdout.as_bytes())
+            .into_iter()
+            .map(|b| {
+                assert_eq!(b.binary, "foo");
+                (b.name, b.ns, b.spread)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn libtest() {
+        assert_eq!(
+            parse(
+                "running 2 tests\n\
+                 test a   ... bench:       1,234 ns/iter (+/- 56)\n\
+                 test b::c ... bench:          12.50 ns/iter (+/- 0.25)\n\
+                 test d ... bench: 7 ns/iter\n\
+                 \n\
+                 test result: ok. 0 passed; 0 failed; 0 ignored; 3 measured\n"
+            ),
+            [
+                ("a".to_string(), 1234.0, Some(56.0)),
+                ("b::c".to_string(), 12.5, Some(0.25)),
+                ("d".to_string(), 7.0, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn criterion() {
+        assert_eq!(
+            parse(
+                "fib 20                  time:   [26.029 us 26.251 µs 26.505 us]\n\
+                 a very long benchmark name\n\
+                 \x20                       time:   [1.0000 ms 2.0000 ms 3.0000 ms]\n\
+                 \x20                       change: [-1.0% +0.5% +2.0%] (p = 0.50 > 0.05)\n\
+                 fast                    time:   [250.00 ps 500.00 ps 750.00 ps]\n\
+                 slow                    time:   [1.5000 s 1.5000 s 1.5000 s]\n"
+            ),
+            [
+                ("fib 20".to_string(), 26251.0, Some(238.0)),
+                (
+                    "a very long benchmark name".to_string(),
+                    2_000_000.0,
+                    Some(1_000_000.0)
+                ),
+                ("fast".to_string(), 0.5, Some(0.25)),
+                ("slow".to_string(), 1_500_000_000.0, Some(0.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert_eq!(
+            parse(
+                "test a ... ok\n\
+                 test b ... bench: x ns/iter (+/- 1)\n\
+                 test c ... bench: 5 ns/iter (+/- x)\n\
+                 test d ... bench: 5 ms/iter\n\
+                 e time: [1 ns 2 ns]\n\
+                 f time: [1 ns 2 ns 3 weeks]\n\
+                 g time: 1 ns 2 ns 3 ns\n"
+            ),
+            [("c".to_string(), 5.0, None)]
+        );
+    }
+}
@@ -1,10 +1,12 @@
 use anyhow::{bail, Context, Result};
+use std::env;
 use std::fs::{self, File};
 use std::path::{Path, PathBuf};
 
 pub struct Cache {
     all_versions_root: PathBuf,
     root: PathBuf,
+    wasm_root: PathBuf,
 }
 
 impl Cache {
@@ -17,9 +19,14 @@ impl Cache {
             },
         };
         let root = all_versions_root.join(env!("CARGO_PKG_VERSION"));
+        let wasm_root = match env::var_os("CARGO_WASI_WASM_CACHE_DIR") {
+            Some(dir) => dir.into(),
+            None => root.join("wasm"),
+        };
         Ok(Cache {
             all_versions_root,
             root,
+            wasm_root,
         })
     }
 
@@ -33,6 +40,25 @@ impl Cache {
         &self.all_versions_root
     }
 
+    /// Returns the path of the list of packages installed by `cargo wasi
+    /// install`, which all versions of `cargo-wasi` share.
+    pub fn installed(&self) -> PathBuf {
+        self.all_versions_root.join("installed.json")
+    }
+
+    /// Returns the path of the content-addressed cache of post-processed wasm
+    /// files, which can be relocated with `$CARGO_WASI_WASM_CACHE_DIR`.
+    pub fn wasm_root(&self) -> &Path {
+        &self.wasm_root
+    }
+
+    /// Get the post-processed wasm file cached under the given key.
+    pub fn wasm(&self, key: &str) -> CachedWasm {
+        CachedWasm {
+            path: self.wasm_root.join(format!("{}.wasm", key)),
+        }
+    }
+
     /// Get the cache stamp with the given name.
     pub fn stamp(&self, name: impl AsRef<str>) -> Stamp {
         let name = name.as_ref();
@@ -97,3 +123,46 @@ impl Stamp {
         self.create()
     }
 }
+
+/// A post-processed wasm file in the cache, keyed by a hash of everything
+/// that went into producing it.
+pub struct CachedWasm {
+    path: PathBuf,
+}
+
+impl CachedWasm {
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Copies the cached wasm file to `dst`.
+    ///
+    /// This is a copy rather than a hard link so that nothing writing to `dst`
+    /// later on can corrupt the cache.
+    pub fn copy_to(&self, dst: &Path) -> Result<()> {
+        drop(fs::remove_file(dst));
+        fs::copy(&self.path, dst)
+            .with_context(|| format!("failed to copy cached wasm file: {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Stores `src` in the cache under this entry's key.
+    ///
+    /// The file is written to a temporary file and then renamed into place so
+    /// that concurrent builds never observe a partially written entry.
+    pub fn store(&self, src: &Path) -> Result<()> {
+        let dir = self.path.parent().unwrap();
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create cache directory: {}", dir.display()))?;
+        let temp = tempfile::NamedTempFile::new_in(dir)
+            .context("failed to create temporary file in cache")?;
+        fs::copy(src, temp.path())?;
+        temp.persist(&self.path).with_context(|| {
+            format!(
+                "failed to store wasm file in cache: {}",
+                self.path.display()
+            )
+        })?;
+        Ok(())
+    }
+}
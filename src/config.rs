@@ -1,5 +1,6 @@
 use crate::{Cache, ToolPath};
 use anyhow::Result;
+use std::io::Write;
 use std::path::PathBuf;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -47,11 +48,14 @@ impl Config {
     }
 
     pub fn status(&self, name: &str, rest: &str) {
-        let mut shell = StandardStream::stderr(self.choice);
+        // Lock stderr for the whole line since artifacts are processed in
+        // parallel and their status lines shouldn't interleave.
+        let shell = StandardStream::stderr(self.choice);
+        let mut shell = shell.lock();
         drop(shell.set_color(ColorSpec::new().set_fg(Some(Color::Green)).set_bold(true)));
-        eprint!("{:>12}", name);
+        drop(write!(shell, "{:>12}", name));
         drop(shell.reset());
-        eprintln!(" {}", rest);
+        drop(writeln!(shell, " {}", rest));
     }
 
     pub fn print_error(&self, err: &anyhow::Error) {
@@ -116,6 +120,39 @@ impl Config {
         (path, is_overridden)
     }
 
+    /// Get the path to our `wizer` tool for the given version, which may be
+    /// the path to install it to if missing, and whether the path has been
+    /// overridden.
+    ///
+    /// Overridable via setting the `WIZER=path/to/wizer` env var.
+    pub fn get_wizer(&self, version: &str) -> (PathBuf, bool) {
+        let (mut path, is_overridden) = self.get_tool("wizer", Some(version));
+        if !is_overridden {
+            path.set_extension(std::env::consts::EXE_EXTENSION);
+        }
+        (path, is_overridden)
+    }
+
+    /// Get the path to the `kind` of WASI preview1 adapter, either `command`
+    /// or `reactor`, used to turn core wasm modules into components. This may
+    /// be the cache path where it should be downloaded to if missing.
+    ///
+    /// Overridable via setting the `WASI_COMMAND_ADAPTER=path/to/adapter.wasm`
+    /// and `WASI_REACTOR_ADAPTER` env vars.
+    pub fn get_wasi_adapter(&self, kind: &str, version: &str) -> ToolPath {
+        let (path, is_overridden) = self.get_tool(&format!("wasi-{}-adapter", kind), Some(version));
+        if is_overridden {
+            return ToolPath::Overridden(path);
+        }
+        let file = PathBuf::from(format!("wasi_snapshot_preview1.{}.wasm", kind));
+        let base = path.parent().unwrap().to_path_buf();
+        ToolPath::Cached {
+            bin_path: base.join(&file),
+            base,
+            sub_paths: vec![file],
+        }
+    }
+
     /// Get the path to our `wasm-opt`, which may be the cache path where it
     /// should be download to if missing, and whether the path has been
     /// overridden.
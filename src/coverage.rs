@@ -0,0 +1,741 @@
+use crate::config::Config;
+use crate::utils::{read_uleb128, CommandExt};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use walrus::ir::{BinaryOp, Call, ExtendedLoad, Instr, InstrLocId, LoadKind, MemArg};
+use walrus::ir::{StoreKind, UnaryOp};
+use walrus::{ExportItem, FunctionBuilder, FunctionId, InstrSeqBuilder, LocalId, MemoryId};
+use walrus::{Module, ValType};
+
+/// The path in the guest of the directory that instrumented wasm files write
+/// their profiles to, which they find by name among their preopened
+/// directories since runtimes don't all preopen them in the order given.
+pub const GUEST_DIR: &str = "/cargo-wasi-coverage";
+
+/// The format of raw profiles that we write, which is the one of LLVM 19 to
+/// 22. Newer LLVM versions may change it, so they're rejected until checked.
+const RAW_VERSION: u64 = 10;
+const MIN_LLVM_VERSION: u32 = 19;
+const MAX_LLVM_VERSION: u32 = 22;
+
+/// `INSTR_PROF_RAW_MAGIC_32`, since wasm32 pointers are 32 bits.
+const RAW_MAGIC: u64 = (255 << 56)
+    | ((b'l' as u64) << 48)
+    | ((b'p' as u64) << 40)
+    | ((b'r' as u64) << 32)
+    | ((b'o' as u64) << 24)
+    | ((b'f' as u64) << 16)
+    | ((b'R' as u64) << 8)
+    | 129;
+
+/// The size of each `__llvm_profile_data` record for wasm32.
+const DATA_SIZE: u32 = 48;
+
+/// `IPVK_Last`, the last kind of value profiling LLVM knows about.
+const VALUE_KIND_LAST: u64 = 2;
+
+/// Where the dump function lays out what it writes in the page of memory it
+/// grows for itself.
+const HEADER: u32 = 0;
+const IOVECS: u32 = 128;
+const FD: u32 = 256;
+const NWRITTEN: u32 = 260;
+const RANDOM: u32 = 264;
+const ZEROES: u32 = 272;
+const PRESTAT: u32 = 280;
+const DIR_NAME: u32 = 288;
+const PATH: u32 = 512;
+
+/// The LLVM tools that turn raw profiles into reports.
+pub struct Tools {
+    profdata: PathBuf,
+    cov: PathBuf,
+}
+
+/// Checks that the toolchain is able to build for coverage and finds the
+/// LLVM tools matching it, before we build anything.
+///
+/// The profiler runtime isn't shipped for wasm targets, so we build without
+/// it using `-Z no-profiler-runtime`, which requires a nightly toolchain, and
+/// write profiles with our own code instead.
+pub fn check_toolchain() -> Result<Tools> {
+    let version = Command::new("rustc").arg("-vV").capture_stdout()?;
+    let field = |name: &str| {
+        version
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    let release = field("release:");
+    let nightly = release.contains("-nightly") || release.contains("-dev");
+    if !nightly && env::var_os("RUSTC_BOOTSTRAP").is_none() {
+        bail!(
+            "`cargo wasi coverage` requires a nightly toolchain, since \
+             rustc {} doesn't ship a profiler runtime for wasm, you'll want \
+             to run:\n\n\
+             \tcargo +nightly wasi coverage\n",
+            release,
+        );
+    }
+    let llvm = field("LLVM version:");
+    let major = llvm.split('.').next().and_then(|s| s.parse::<u32>().ok());
+    if major.is_none_or(|major| major < MIN_LLVM_VERSION) {
+        bail!(
+            "`cargo wasi coverage` requires a toolchain using LLVM {} or \
+             later, but rustc {} uses LLVM {}",
+            MIN_LLVM_VERSION,
+            release,
+            llvm,
+        );
+    }
+    if major.is_some_and(|major| major > MAX_LLVM_VERSION) {
+        bail!(
+            "`cargo wasi coverage` only supports toolchains using LLVM {} to \
+             {}, whose raw profile format it writes, but rustc {} uses LLVM {}",
+            MIN_LLVM_VERSION,
+            MAX_LLVM_VERSION,
+            release,
+            llvm,
+        );
+    }
+
+    let sysroot = Command::new("rustc")
+        .arg("--print")
+        .arg("sysroot")
+        .capture_stdout()?;
+    let bin = Path::new(sysroot.trim())
+        .join("lib/rustlib")
+        .join(field("host:"))
+        .join("bin");
+    Ok(Tools {
+        profdata: find_tool("llvm-profdata", "LLVM_PROFDATA", &bin)?,
+        cov: find_tool("llvm-cov", "LLVM_COV", &bin)?,
+    })
+}
+
+/// Finds an LLVM tool through the `var` env var, then in the `llvm-tools`
+/// component of the toolchain, and then in `$PATH`.
+fn find_tool(name: &str, var: &str, rustup_bin: &Path) -> Result<PathBuf> {
+    if let Some(path) = env::var_os(var) {
+        return Ok(PathBuf::from(path));
+    }
+    let path = rustup_bin
+        .join(name)
+        .with_extension(env::consts::EXE_EXTENSION);
+    if path.exists() {
+        return Ok(path);
+    }
+    match which::which(name) {
+        Ok(path) => Ok(path),
+        Err(_) => bail!(
+            "failed to find `{0}`, which `cargo wasi coverage` uses to produce \
+             reports, you'll want to install the LLVM tools of your toolchain:\n\n\
+             \trustup component add llvm-tools\n\n\
+             or set `${1}` to the path of a `{0}` matching its LLVM version\n",
+            name,
+            var,
+        ),
+    }
+}
+
+/// Returns the `CARGO_ENCODED_RUSTFLAGS` that build for coverage, keeping any
+/// flags the user set.
+pub fn rustflags() -> OsString {
+    let mut flags = match env::var("CARGO_ENCODED_RUSTFLAGS") {
+        Ok(flags) if !flags.is_empty() => flags.split('\x1f').map(String::from).collect(),
+        _ => match env::var("RUSTFLAGS") {
+            Ok(flags) => flags.split_whitespace().map(String::from).collect(),
+            Err(_) => Vec::new(),
+        },
+    };
+    flags.push("-Cinstrument-coverage".to_string());
+    flags.push("-Zno-profiler-runtime".to_string());
+    flags.join("\x1f").into()
+}
+
+/// The location and size of a data segment in linear memory.
+#[derive(Clone, Copy)]
+struct Segment {
+    offset: u32,
+    len: u32,
+}
+
+/// Adds code to the instrumented module `module`, parsed from `bytes`, which
+/// writes its profile counters to a `*.profraw` file in `GUEST_DIR` when it
+/// exits, doing the job of LLVM's profiler runtime.
+///
+/// Everything in a raw profile besides the counters is known statically, so
+/// we build its header here and have the module write it with the sections
+/// of its memory that LLVM laid out for the profiler runtime. Modules that
+/// weren't instrumented in the first place are left alone.
+pub fn instrument(module: &mut Module, bytes: &[u8], name: &str) -> Result<()> {
+    let segments = profile_segments(bytes).context("failed to find profile data segments")?;
+    let counters = match segments.get("__llvm_prf_cnts") {
+        Some(counters) => *counters,
+        None => return Ok(()),
+    };
+    let (data, names) = match (
+        segments.get("__llvm_prf_data"),
+        segments.get("__llvm_prf_names"),
+    ) {
+        (Some(data), Some(names)) => (*data, *names),
+        _ => bail!("instrumented module is missing profile data or names"),
+    };
+    let bitmap = segments
+        .get("__llvm_prf_bits")
+        .copied()
+        .unwrap_or(Segment { offset: 0, len: 0 });
+    if data.len % DATA_SIZE != 0 {
+        bail!("unsupported layout of profile data, which isn't the one of LLVM 19 to 22");
+    }
+    let memory = match module.memories.iter().next() {
+        Some(memory) => memory.id(),
+        None => bail!("instrumented module has no memory"),
+    };
+
+    let header = [
+        RAW_MAGIC,
+        RAW_VERSION,
+        0, // BinaryIdsSize
+        u64::from(data.len / DATA_SIZE),
+        0, // PaddingBytesBeforeCounters
+        u64::from(counters.len / 8),
+        padding(counters.len),
+        u64::from(bitmap.len),
+        padding(bitmap.len),
+        u64::from(names.len),
+        u64::from(counters.offset.wrapping_sub(data.offset)),
+        u64::from(bitmap.offset.wrapping_sub(data.offset)),
+        u64::from(names.offset),
+        0, // NumVTables
+        0, // VNamesSize
+        VALUE_KIND_LAST,
+    ];
+    let header = header
+        .iter()
+        .flat_map(|field| field.to_le_bytes().to_vec())
+        .collect::<Vec<_>>();
+    // The header and padding are in our page, and the rest is where LLVM put
+    // it.
+    let iovecs = [
+        (true, HEADER, header.len() as u32),
+        (false, data.offset, data.len),
+        (false, counters.offset, counters.len),
+        (true, ZEROES, padding(counters.len) as u32),
+        (false, bitmap.offset, bitmap.len),
+        (true, ZEROES, padding(bitmap.len) as u32),
+        (false, names.offset, names.len),
+        (true, ZEROES, padding(names.len) as u32),
+    ];
+    let iovecs = iovecs
+        .iter()
+        .filter(|(_, _, len)| *len > 0)
+        .collect::<Vec<_>>();
+    // The random part of the name makes each process write its own file.
+    let prefix = format!("{}-", name);
+    let path = format!("{}{}.profraw", prefix, "0".repeat(16));
+
+    let wasi = Wasi::new(module);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    let base = module.locals.add(ValType::I32);
+    let nibble = module.locals.add(ValType::I32);
+    let dir = module.locals.add(ValType::I32);
+    let mut body = builder.func_body();
+
+    body.i32_const(1)
+        .memory_grow(memory)
+        .local_tee(base)
+        .i32_const(-1)
+        .binop(BinaryOp::I32Eq)
+        .if_else(
+            None,
+            |then| {
+                then.return_();
+            },
+            |_| {},
+        )
+        .local_get(base)
+        .i32_const(16)
+        .binop(BinaryOp::I32Shl)
+        .local_set(base);
+    store_bytes(&mut body, memory, base, HEADER, &header);
+    store_bytes(&mut body, memory, base, PATH, path.as_bytes());
+    for (i, (in_page, offset, len)) in iovecs.iter().enumerate() {
+        let at = IOVECS + i as u32 * 8;
+        body.local_get(base);
+        if *in_page {
+            body.local_get(base)
+                .i32_const(*offset as i32)
+                .binop(BinaryOp::I32Add);
+        } else {
+            body.i32_const(*offset as i32);
+        }
+        store(&mut body, memory, StoreKind::I32 { atomic: false }, at);
+        body.local_get(base).i32_const(*len as i32);
+        store(&mut body, memory, StoreKind::I32 { atomic: false }, at + 4);
+    }
+
+    body.local_get(base)
+        .i32_const(RANDOM as i32)
+        .binop(BinaryOp::I32Add)
+        .i32_const(8)
+        .call(wasi.random_get)
+        .drop();
+    for i in 0..16 {
+        body.local_get(base).local_get(base).load(
+            memory,
+            LoadKind::I32_8 {
+                kind: ExtendedLoad::ZeroExtend,
+            },
+            MemArg {
+                align: 1,
+                offset: RANDOM + i / 2,
+            },
+        );
+        if i % 2 == 0 {
+            body.i32_const(4).binop(BinaryOp::I32ShrU);
+        } else {
+            body.i32_const(15).binop(BinaryOp::I32And);
+        }
+        body.local_tee(nibble)
+            .i32_const(i32::from(b'0'))
+            .i32_const(i32::from(b'a') - 10)
+            .local_get(nibble)
+            .i32_const(10)
+            .binop(BinaryOp::I32LtU)
+            .select(None)
+            .binop(BinaryOp::I32Add);
+        let at = PATH + prefix.len() as u32 + i;
+        store(&mut body, memory, StoreKind::I32_8 { atomic: false }, at);
+    }
+
+    // Preopened directories are numbered from 3 and `fd_prestat_get` fails
+    // past the last of them, in which case there's nowhere to write to.
+    body.i32_const(3).local_set(dir);
+    body.block(None, |found| {
+        let found_id = found.id();
+        found.loop_(None, |next| {
+            let next_id = next.id();
+            next.local_get(dir)
+                .local_get(base)
+                .i32_const(PRESTAT as i32)
+                .binop(BinaryOp::I32Add)
+                .call(wasi.fd_prestat_get)
+                .if_else(
+                    None,
+                    |then| {
+                        then.return_();
+                    },
+                    |_| {},
+                )
+                .local_get(base)
+                .load(
+                    memory,
+                    LoadKind::I32 { atomic: false },
+                    MemArg {
+                        align: 4,
+                        offset: PRESTAT + 4,
+                    },
+                )
+                .i32_const(GUEST_DIR.len() as i32)
+                .binop(BinaryOp::I32Eq)
+                .if_else(
+                    None,
+                    |then| {
+                        then.local_get(dir)
+                            .local_get(base)
+                            .i32_const(DIR_NAME as i32)
+                            .binop(BinaryOp::I32Add)
+                            .i32_const(GUEST_DIR.len() as i32)
+                            .call(wasi.fd_prestat_dir_name)
+                            .unop(UnaryOp::I32Eqz);
+                        compare_bytes(then, memory, base, DIR_NAME, GUEST_DIR.as_bytes());
+                        then.br_if(found_id);
+                    },
+                    |_| {},
+                )
+                .local_get(dir)
+                .i32_const(1)
+                .binop(BinaryOp::I32Add)
+                .local_set(dir)
+                .br(next_id);
+        });
+    });
+
+    // path_open(dir, 0, path, len, O_CREAT | O_EXCL, FD_WRITE, 0, 0, &fd)
+    body.local_get(dir)
+        .i32_const(0)
+        .local_get(base)
+        .i32_const(PATH as i32)
+        .binop(BinaryOp::I32Add)
+        .i32_const(path.len() as i32)
+        .i32_const(1 | 4)
+        .i64_const(1 << 6)
+        .i64_const(0)
+        .i32_const(0)
+        .local_get(base)
+        .i32_const(FD as i32)
+        .binop(BinaryOp::I32Add)
+        .call(wasi.path_open)
+        .if_else(
+            None,
+            |then| {
+                then.return_();
+            },
+            |_| {},
+        );
+    // Runtimes may only write the first of several buffers at once, so they
+    // are written one by one.
+    for i in 0..iovecs.len() {
+        load_fd(&mut body, memory, base);
+        body.local_get(base)
+            .i32_const((IOVECS + i as u32 * 8) as i32)
+            .binop(BinaryOp::I32Add)
+            .i32_const(1)
+            .local_get(base)
+            .i32_const(NWRITTEN as i32)
+            .binop(BinaryOp::I32Add)
+            .call(wasi.fd_write)
+            .drop();
+    }
+    load_fd(&mut body, memory, base);
+    body.call(wasi.fd_close).drop();
+    let dump = builder.finish(Vec::new(), &mut module.funcs);
+    module.funcs.get_mut(dump).name = Some("cargo_wasi_dump_profile".to_string());
+
+    dump_before_exit(module, dump, wasi.proc_exit);
+    dump_after_start(module, dump)
+}
+
+/// The number of zero bytes LLVM pads a section of `len` bytes with.
+fn padding(len: u32) -> u64 {
+    u64::from((8 - len % 8) % 8)
+}
+
+fn store(body: &mut InstrSeqBuilder, memory: MemoryId, kind: StoreKind, offset: u32) {
+    let align = match kind {
+        StoreKind::I32_8 { .. } => 1,
+        StoreKind::I32 { .. } => 4,
+        _ => 8,
+    };
+    body.store(memory, kind, MemArg { align, offset });
+}
+
+/// Stores `bytes` at `offset` in the page starting at `base`.
+fn store_bytes(
+    body: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    base: LocalId,
+    offset: u32,
+    bytes: &[u8],
+) {
+    for (i, chunk) in bytes.chunks(8).enumerate() {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        body.local_get(base).i64_const(i64::from_le_bytes(word));
+        let at = offset + i as u32 * 8;
+        store(body, memory, StoreKind::I64 { atomic: false }, at);
+    }
+}
+
+/// Ands the condition on the stack with whether the page starting at `base`
+/// holds `bytes` at `offset`, followed by zeroes up to a multiple of 8 bytes.
+fn compare_bytes(
+    body: &mut InstrSeqBuilder,
+    memory: MemoryId,
+    base: LocalId,
+    offset: u32,
+    bytes: &[u8],
+) {
+    for (i, chunk) in bytes.chunks(8).enumerate() {
+        let mut word = [0; 8];
+        word[..chunk.len()].copy_from_slice(chunk);
+        body.local_get(base)
+            .load(
+                memory,
+                LoadKind::I64 { atomic: false },
+                MemArg {
+                    align: 8,
+                    offset: offset + i as u32 * 8,
+                },
+            )
+            .i64_const(i64::from_le_bytes(word))
+            .binop(BinaryOp::I64Eq)
+            .binop(BinaryOp::I32And);
+    }
+}
+
+fn load_fd(body: &mut InstrSeqBuilder, memory: MemoryId, base: LocalId) {
+    body.local_get(base).load(
+        memory,
+        LoadKind::I32 { atomic: false },
+        MemArg {
+            align: 4,
+            offset: FD,
+        },
+    );
+}
+
+/// Makes every call to `proc_exit` dump the profile first.
+fn dump_before_exit(module: &mut Module, dump: FunctionId, proc_exit: FunctionId) {
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    let code = module.locals.add(ValType::I32);
+    builder
+        .func_body()
+        .call(dump)
+        .local_get(code)
+        .call(proc_exit);
+    let hook = builder.finish(vec![code], &mut module.funcs);
+    module.funcs.get_mut(hook).name = Some("cargo_wasi_proc_exit".to_string());
+
+    struct Redirect {
+        from: FunctionId,
+        to: FunctionId,
+    }
+    impl walrus::ir::VisitorMut for Redirect {
+        fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+            if let Instr::Call(Call { func }) = instr {
+                if *func == self.from {
+                    *func = self.to;
+                }
+            }
+        }
+    }
+    let mut redirect = Redirect {
+        from: proc_exit,
+        to: hook,
+    };
+    for (id, func) in module.funcs.iter_local_mut() {
+        if id != hook {
+            let entry = func.entry_block();
+            walrus::ir::dfs_pre_order_mut(&mut redirect, func, entry);
+        }
+    }
+}
+
+/// Makes `_start` dump the profile when it returns, which is how a command
+/// exits successfully.
+fn dump_after_start(module: &mut Module, dump: FunctionId) -> Result<()> {
+    let export = module
+        .exports
+        .iter_mut()
+        .find(|export| export.name == "_start");
+    let export = match export {
+        Some(export) => export,
+        None => bail!("instrumented module has no `_start` function"),
+    };
+    let start = match export.item {
+        ExportItem::Function(start) => start,
+        _ => bail!("`_start` export of instrumented module isn't a function"),
+    };
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder.func_body().call(start).call(dump);
+    let hook = builder.finish(Vec::new(), &mut module.funcs);
+    module.funcs.get_mut(hook).name = Some("cargo_wasi_start".to_string());
+    export.item = ExportItem::Function(hook);
+    Ok(())
+}
+
+/// The WASI functions that dumping a profile calls, imported if the module
+/// doesn't already import them.
+struct Wasi {
+    fd_prestat_get: FunctionId,
+    fd_prestat_dir_name: FunctionId,
+    path_open: FunctionId,
+    fd_write: FunctionId,
+    fd_close: FunctionId,
+    random_get: FunctionId,
+    proc_exit: FunctionId,
+}
+
+impl Wasi {
+    fn new(module: &mut Module) -> Wasi {
+        use ValType::{I32, I64};
+
+        let mut import = |name: &str, params: &[ValType], results: &[ValType]| {
+            let existing = module
+                .imports
+                .find("wasi_snapshot_preview1", name)
+                .map(|id| &module.imports.get(id).kind);
+            if let Some(walrus::ImportKind::Function(func)) = existing {
+                return *func;
+            }
+            let ty = module.types.add(params, results);
+            module.add_import_func("wasi_snapshot_preview1", name, ty).0
+        };
+        Wasi {
+            fd_prestat_get: import("fd_prestat_get", &[I32, I32], &[I32]),
+            fd_prestat_dir_name: import("fd_prestat_dir_name", &[I32, I32, I32], &[I32]),
+            path_open: import(
+                "path_open",
+                &[I32, I32, I32, I32, I32, I64, I64, I32, I32],
+                &[I32],
+            ),
+            fd_write: import("fd_write", &[I32, I32, I32, I32], &[I32]),
+            fd_close: import("fd_close", &[I32], &[I32]),
+            random_get: import("random_get", &[I32, I32], &[I32]),
+            proc_exit: import("proc_exit", &[I32], &[]),
+        }
+    }
+}
+
+/// Finds the data segments that LLVM names `__llvm_prf_*` in the module
+/// `bytes`, using the segment names of its name section.
+fn profile_segments(bytes: &[u8]) -> Result<HashMap<String, Segment>> {
+    let mut segments = Vec::new();
+    let mut names = HashMap::new();
+    let mut data = bytes.get(8..).context("not a wasm module")?;
+    while let Some((&id, mut rest)) = data.split_first() {
+        let len = read_uleb128(&mut rest).context("invalid section size")? as usize;
+        let section = rest.get(..len).context("section extends past the end")?;
+        data = &rest[len..];
+        match id {
+            11 => segments = data_segments(section).context("invalid data section")?,
+            0 => {
+                let mut contents = section;
+                let name_len =
+                    read_uleb128(&mut contents).context("invalid section name")? as usize;
+                let name = contents
+                    .get(..name_len)
+                    .context("section name extends past the end")?;
+                if name == b"name" {
+                    names = segment_names(&contents[name_len..]).context("invalid name section")?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(names
+        .into_iter()
+        .filter(|(_, name)| name.starts_with("__llvm_prf_"))
+        .filter_map(|(index, name)| Some((name, (*segments.get(index as usize)?)?)))
+        .collect())
+}
+
+/// Returns the location of each data segment, or `None` for those which
+/// aren't at a constant address.
+fn data_segments(mut data: &[u8]) -> Option<Vec<Option<Segment>>> {
+    let count = read_uleb128(&mut data)?;
+    let mut segments = Vec::new();
+    for _ in 0..count {
+        let offset = match read_uleb128(&mut data)? {
+            1 => None,
+            flags => {
+                if flags == 2 {
+                    read_uleb128(&mut data)?;
+                }
+                // An `i32.const` followed by `end` is a constant address,
+                // which is never negative in practice.
+                let (&op, mut rest) = data.split_first()?;
+                let offset = read_uleb128(&mut rest);
+                let (&end, rest) = rest.split_first()?;
+                if op == 0x41 && end == 0x0b {
+                    data = rest;
+                    offset
+                } else {
+                    return None;
+                }
+            }
+        };
+        let len = read_uleb128(&mut data)?;
+        data = data.get(len as usize..)?;
+        segments.push(offset.map(|offset| Segment { offset, len }));
+    }
+    Some(segments)
+}
+
+/// Returns the names of data segments, from subsection 9 of the name section.
+fn segment_names(mut data: &[u8]) -> Option<HashMap<u32, String>> {
+    let mut names = HashMap::new();
+    while let Some((&id, mut rest)) = data.split_first() {
+        let len = read_uleb128(&mut rest)? as usize;
+        let mut contents = rest.get(..len)?;
+        data = &rest[len..];
+        if id != 9 {
+            continue;
+        }
+        let count = read_uleb128(&mut contents)?;
+        for _ in 0..count {
+            let index = read_uleb128(&mut contents)?;
+            let len = read_uleb128(&mut contents)? as usize;
+            let name = contents.get(..len)?;
+            contents = &contents[len..];
+            names.insert(index, String::from_utf8_lossy(name).into_owned());
+        }
+    }
+    Some(names)
+}
+
+/// Merges the raw profiles written to `profiles` and writes an lcov report
+/// and an HTML report to `output` for the instrumented wasm files `objects`,
+/// printing a summary as well.
+pub fn report(
+    tools: &Tools,
+    profiles: &Path,
+    objects: &[PathBuf],
+    output: &Path,
+    config: &Config,
+) -> Result<()> {
+    let mut raw = Vec::new();
+    for entry in fs::read_dir(profiles)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("profraw") {
+            raw.push(path);
+        }
+    }
+    if raw.is_empty() {
+        bail!(
+            "no coverage profiles were written, which happens when every \
+             wasm file traps before exiting"
+        );
+    }
+    raw.sort();
+
+    let profdata = output.join("coverage.profdata");
+    config.status("Merging", &format!("{} coverage profiles", raw.len()));
+    Command::new(&tools.profdata)
+        .arg("merge")
+        .arg("-sparse")
+        .arg("-o")
+        .arg(&profdata)
+        .args(&raw)
+        .run()?;
+
+    let llvm_cov = |subcommand: &str| {
+        let mut cmd = Command::new(&tools.cov);
+        cmd.arg(subcommand)
+            .arg(format!("-instr-profile={}", profdata.display()))
+            // Leave out the standard library and dependencies.
+            .arg("-ignore-filename-regex=/rustc/|[/\\\\]\\.cargo[/\\\\](registry|git)[/\\\\]");
+        for (i, object) in objects.iter().enumerate() {
+            if i > 0 {
+                cmd.arg("-object");
+            }
+            cmd.arg(object);
+        }
+        cmd
+    };
+
+    let lcov = output.join("lcov.info");
+    config.status("Writing", &format!("`{}`", lcov.display()));
+    let info = llvm_cov("export").arg("-format=lcov").capture_stdout()?;
+    fs::write(&lcov, info).context(format!("failed to write `{}`", lcov.display()))?;
+
+    let html = output.join("html");
+    config.status(
+        "Writing",
+        &format!("`{}`", html.join("index.html").display()),
+    );
+    llvm_cov("show")
+        .arg("-format=html")
+        .arg(format!("-output-dir={}", html.display()))
+        .run()?;
+
+    llvm_cov("report").run()
+}
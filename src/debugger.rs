@@ -0,0 +1,179 @@
+use crate::args;
+use crate::config::Config;
+use crate::debuginfo;
+use crate::utils;
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// The lldb command that makes it load the code that runtimes register with
+/// the GDB JIT interface, which is only on by default on some platforms.
+const LLDB_JIT_LOADER: &str = "settings set plugin.jit-loader.gdb.enable on";
+
+/// The native debuggers that `debug` knows how to launch.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Debugger {
+    Lldb,
+    Gdb,
+}
+
+impl Debugger {
+    fn name(&self) -> &'static str {
+        match self {
+            Debugger::Lldb => "lldb",
+            Debugger::Gdb => "gdb",
+        }
+    }
+}
+
+/// The options of `cargo wasi debug`, which are our own rather than Cargo's.
+pub struct Options {
+    debugger: Option<Debugger>,
+    vscode: bool,
+}
+
+impl Options {
+    /// Takes our own options out of `args`, returning the rest to pass to
+    /// `cargo run`.
+    pub fn parse(args: Vec<OsString>) -> Result<(Options, Vec<OsString>)> {
+        let mut options = Options {
+            debugger: None,
+            vscode: false,
+        };
+        let (own, rest) = args::split_options(args, &["--vscode"], &["--debugger"])?;
+        for (flag, value) in own {
+            let value = value.unwrap_or_default();
+            match (flag, value.to_string_lossy().as_ref()) {
+                ("--vscode", _) => options.vscode = true,
+                (_, "lldb") => options.debugger = Some(Debugger::Lldb),
+                (_, "gdb") => options.debugger = Some(Debugger::Gdb),
+                (_, value) => bail!(
+                    "invalid value for `--debugger`: `{}`, expected `lldb` or `gdb`",
+                    value
+                ),
+            }
+        }
+        Ok((options, rest))
+    }
+
+    /// The debugger to use, which is the first of lldb and gdb in $PATH
+    /// unless `--debugger` picks one.
+    ///
+    /// VS Code launches the debugger through an extension of its own, so
+    /// lldb is assumed for it if neither is installed.
+    fn debugger(&self) -> Result<Debugger> {
+        if let Some(debugger) = self.debugger {
+            return Ok(debugger);
+        }
+        let found = [Debugger::Lldb, Debugger::Gdb]
+            .iter()
+            .copied()
+            .find(|debugger| which::which(debugger.name()).is_ok());
+        match found {
+            Some(debugger) => Ok(debugger),
+            None if self.vscode => Ok(Debugger::Lldb),
+            None => bail!(
+                "failed to find `lldb` or `gdb` in $PATH, you'll want to \
+                 install one of them before running this command"
+            ),
+        }
+    }
+}
+
+/// Checks that the debugger exists before we build anything.
+pub fn check_installed(options: &Options) -> Result<()> {
+    let debugger = options.debugger()?;
+    if !options.vscode && which::which(debugger.name()).is_err() {
+        bail!(
+            "failed to find `{}` in $PATH, you'll want to install \
+             `{}` before running this command",
+            debugger.name(),
+            debugger.name(),
+        );
+    }
+    Ok(())
+}
+
+/// Launches the debugger on `cmd`, which runs the runtime on `wasm` with its
+/// debug info enabled, or prints a VS Code configuration that does so if
+/// `--vscode` was passed.
+pub fn launch(options: &Options, cmd: &Command, wasm: &str, config: &Config) -> Result<()> {
+    let debugger = options.debugger()?;
+    let bytes = fs::read(wasm).context(format!("failed to read `{}`", wasm))?;
+    if !debuginfo::has_dwarf(&bytes) {
+        config.info(&format!(
+            "`{}` has no DWARF debuginfo, so the debugger won't be able to \
+             show its source, you'll want to build with `debug = true` in \
+             the profile and without `wasm-split-debuginfo`",
+            wasm
+        ));
+    }
+
+    if options.vscode {
+        let launch = launch_json(debugger, cmd, wasm)?;
+        println!("{}", serde_json::to_string_pretty(&launch)?);
+        return Ok(());
+    }
+
+    let mut debug = Command::new(debugger.name());
+    match debugger {
+        Debugger::Lldb => debug.arg("-O").arg(LLDB_JIT_LOADER).arg("--"),
+        Debugger::Gdb => debug.arg("--args"),
+    };
+    debug.arg(cmd.get_program()).args(cmd.get_args());
+    config.status(
+        "Debugging",
+        &format!("`{}` with `{}`", wasm, debugger.name()),
+    );
+    config.verbose(|| config.status("Running", &format!("{:?}", debug)));
+    let status = debug
+        .status()
+        .with_context(|| format!("failed to create process {:?}", debug))?;
+    utils::check_success(&debug, &status, &[], &[])
+}
+
+/// Renders a `launch.json` for VS Code with a configuration for the CodeLLDB
+/// extension or the C/C++ extension's gdb support.
+fn launch_json(debugger: Debugger, cmd: &Command, wasm: &str) -> Result<serde_json::Value> {
+    // Both extensions want the program as a path rather than a name to look
+    // up in $PATH.
+    let program = Path::new(cmd.get_program());
+    let program = which::which(program).unwrap_or_else(|_| program.to_path_buf());
+    let args = cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>();
+    let cwd = env::current_dir()?;
+    let name = format!(
+        "Debug {}",
+        Path::new(wasm).file_name().unwrap().to_string_lossy()
+    );
+    let configuration = match debugger {
+        Debugger::Lldb => json!({
+            "type": "lldb",
+            "request": "launch",
+            "name": name,
+            "program": program,
+            "args": args,
+            "cwd": cwd,
+            "initCommands": [LLDB_JIT_LOADER],
+        }),
+        Debugger::Gdb => json!({
+            "type": "cppdbg",
+            "request": "launch",
+            "name": name,
+            "program": program,
+            "args": args,
+            "cwd": cwd,
+            "MIMode": "gdb",
+        }),
+    };
+    Ok(json!({
+        "version": "0.2.0",
+        "configurations": [configuration],
+    }))
+}
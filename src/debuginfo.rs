@@ -0,0 +1,100 @@
+use crate::utils::{read_uleb128, write_uleb128};
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::Path;
+
+/// Moves the DWARF debug information of the module at `wasm` into a separate
+/// file at `debug_wasm`, in the style of emscripten's `-gseparate-dwarf`.
+///
+/// The separate file is a copy of the module with the DWARF intact, so that
+/// the code offsets DWARF refers to remain valid, while `wasm` has all of its
+/// `.debug_*` custom sections removed and gains an `external_debug_info`
+/// custom section pointing debuggers at `debug_wasm` by its relative path.
+pub fn split(wasm: &Path, debug_wasm: &Path) -> Result<()> {
+    let bytes = fs::read(wasm).with_context(|| format!("failed to read `{}`", wasm.display()))?;
+    let mut stripped = strip_dwarf(&bytes)
+        .with_context(|| format!("failed to parse sections of `{}`", wasm.display()))?;
+
+    let url = debug_wasm.file_name().unwrap().to_str().unwrap();
+    let mut payload = Vec::new();
+    write_name(&mut payload, "external_debug_info");
+    write_name(&mut payload, url);
+    stripped.push(0);
+    write_uleb128(&mut stripped, payload.len() as u32);
+    stripped.extend_from_slice(&payload);
+
+    fs::write(debug_wasm, &bytes)
+        .with_context(|| format!("failed to write `{}`", debug_wasm.display()))?;
+    fs::write(wasm, &stripped).with_context(|| format!("failed to write `{}`", wasm.display()))?;
+    Ok(())
+}
+
+/// Returns a copy of the module `bytes` without its DWARF custom sections.
+fn strip_dwarf(bytes: &[u8]) -> Result<Vec<u8>> {
+    map_custom_sections(bytes, |name, payload| {
+        if name.starts_with(".debug_") || name == "external_debug_info" {
+            Ok(None)
+        } else {
+            Ok(Some(payload.to_vec()))
+        }
+    })
+}
+
+/// Whether the module `bytes` has DWARF for debuggers to map its code back to
+/// source with.
+pub fn has_dwarf(bytes: &[u8]) -> bool {
+    let mut found = false;
+    drop(map_custom_sections(bytes, |name, _| {
+        found |= name == ".debug_info";
+        Ok(None)
+    }));
+    found
+}
+
+/// Returns a copy of the module `bytes` where custom sections are replaced by
+/// the payload `f` returns for them, or removed if it returns `None`.
+///
+/// This works on the raw sections rather than through `walrus` so that every
+/// other section, the code section in particular, is kept byte-for-byte.
+fn map_custom_sections(
+    bytes: &[u8],
+    mut f: impl FnMut(&str, &[u8]) -> Result<Option<Vec<u8>>>,
+) -> Result<Vec<u8>> {
+    if bytes.len() < 8 || &bytes[..4] != b"\0asm" {
+        bail!("not a wasm module");
+    }
+    let mut mapped = bytes[..8].to_vec();
+    let mut data = &bytes[8..];
+    while let Some((&id, mut rest)) = data.split_first() {
+        let len = read_uleb128(&mut rest).context("invalid section size")? as usize;
+        if rest.len() < len {
+            bail!("section extends past the end of the module");
+        }
+        let section_len = data.len() - rest.len() + len;
+        let (section, next) = data.split_at(section_len);
+        data = next;
+
+        if id != 0 {
+            mapped.extend_from_slice(section);
+            continue;
+        }
+        let mut contents = &rest[..len];
+        let name_len = read_uleb128(&mut contents).context("invalid section name")? as usize;
+        let name = contents.get(..name_len).context("invalid section name")?;
+        let name = std::str::from_utf8(name).context("invalid section name")?;
+        if let Some(payload) = f(name, &contents[name_len..])? {
+            let mut custom = Vec::new();
+            write_name(&mut custom, name);
+            custom.extend_from_slice(&payload);
+            mapped.push(0);
+            write_uleb128(&mut mapped, custom.len() as u32);
+            mapped.extend_from_slice(&custom);
+        }
+    }
+    Ok(mapped)
+}
+
+fn write_name(dst: &mut Vec<u8>, name: &str) {
+    write_uleb128(dst, name.len() as u32);
+    dst.extend_from_slice(name.as_bytes());
+}
@@ -0,0 +1,199 @@
+use crate::backtrace;
+use crate::runtime::{Limit, Output, Run};
+use crate::utils::GuestExit;
+use anyhow::{anyhow, Context, Result};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
+use wasmtime::{
+    Engine, GuestProfiler, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, Trap,
+    UpdateDeadline, WasmBacktrace, WasmBacktraceDetails,
+};
+use wasmtime_wasi::p1::{self, WasiP1Ctx};
+use wasmtime_wasi::p2::pipe::MemoryOutputPipe;
+use wasmtime_wasi::{DirPerms, FilePerms, I32Exit, WasiCtxBuilder};
+
+/// Executes `run` with an in-process Wasmtime and WASI preview1.
+///
+/// A nonzero exit code of the program is returned as a `GuestExit` error and
+/// exceeding a limit as a `LimitExceeded` error, while traps are returned with
+/// their wasm backtrace, symbolicated like those of external runtimes. A
+/// profile requested by `run` is written either way.
+pub fn run(run: &Run<'_>) -> Result<()> {
+    let mut builder = WasiCtxBuilder::new();
+    builder.inherit_stdio();
+    execute(run, builder)
+}
+
+/// Executes `run` like `run` does, but captures its stdout and stderr, with
+/// the error of a trap appended to the latter.
+pub fn output(run: &Run<'_>) -> Result<Output> {
+    let stdout = MemoryOutputPipe::new(usize::MAX);
+    let stderr = MemoryOutputPipe::new(usize::MAX);
+    let mut builder = WasiCtxBuilder::new();
+    builder
+        .inherit_stdin()
+        .stdout(stdout.clone())
+        .stderr(stderr.clone());
+    let result = execute(run, builder);
+
+    let mut output = Output {
+        success: result.is_ok(),
+        code: match &result {
+            Ok(()) => Some(0),
+            Err(e) => e.downcast_ref::<GuestExit>().map(|exit| exit.0),
+        },
+        stdout: stdout.contents().to_vec(),
+        stderr: stderr.contents().to_vec(),
+        trap: None,
+    };
+    if let Err(e) = result {
+        if e.downcast_ref::<GuestExit>().is_none() {
+            let trap = format!("{:?}\n", e);
+            output.stderr.extend_from_slice(trap.as_bytes());
+            output.trap = Some(trap);
+        }
+    }
+    Ok(output)
+}
+
+struct Host {
+    wasi: WasiP1Ctx,
+    limits: StoreLimits,
+    profiler: Option<GuestProfiler>,
+}
+
+fn execute(run: &Run<'_>, mut builder: WasiCtxBuilder) -> Result<()> {
+    let limits = run.limits;
+    let mut config = wasmtime::Config::new();
+    config
+        // Wasmtime would read file and line information from the DWARF of the
+        // module, which post-processing leaves stale.
+        .wasm_backtrace_details(WasmBacktraceDetails::Disable)
+        .epoch_interruption(limits.timeout.is_some() || run.profile.is_some())
+        .consume_fuel(limits.fuel.is_some());
+    let engine = Engine::new(&config)?;
+    let module = Module::from_file(&engine, run.wasm)
+        .with_context(|| format!("failed to compile `{}`", run.wasm))?;
+
+    let mut linker = Linker::<Host>::new(&engine);
+    p1::add_to_linker_sync(&mut linker, |host| &mut host.wasi)?;
+
+    builder.arg(run.wasm).args(run.args);
+    for (key, value) in run.env {
+        builder.env(key, value);
+    }
+    for (guest, host) in run.dirs {
+        builder
+            .preopened_dir(host, guest, DirPerms::all(), FilePerms::all())
+            .with_context(|| format!("failed to preopen `{}`", host))?;
+    }
+    let mut store_limits = StoreLimitsBuilder::new();
+    if let Some(bytes) = limits.max_memory {
+        // Trap rather than have `memory.grow` return -1, so that we can tell
+        // the limit was hit.
+        store_limits = store_limits
+            .memory_size(usize::try_from(bytes).unwrap_or(usize::MAX))
+            .trap_on_grow_failure(true);
+    }
+    let mut store = Store::new(
+        &engine,
+        Host {
+            wasi: builder.build_p1(),
+            limits: store_limits.build(),
+            profiler: None,
+        },
+    );
+    store.limiter(|host| &mut host.limits);
+    if let Some(fuel) = limits.fuel {
+        store.set_fuel(fuel)?;
+    }
+
+    // The epoch is bumped once the timeout passes, interrupting the program,
+    // unless it finishes first and hangs up on the timer.
+    let (_done, timer) = mpsc::channel::<()>();
+    if let Some(profile) = run.profile {
+        // The epoch is bumped every interval instead, taking a sample each
+        // time, so it's up to us to notice the timeout.
+        let interval = profile.interval;
+        let modules = vec![(run.wasm.to_string(), module.clone())];
+        store.data_mut().profiler = Some(GuestProfiler::new(run.wasm, interval, modules));
+        store.set_epoch_deadline(1);
+        let start = Instant::now();
+        let timeout = limits.timeout;
+        store.epoch_deadline_callback(move |mut store| {
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Err(Trap::Interrupt.into());
+            }
+            if let Some(mut profiler) = store.data_mut().profiler.take() {
+                profiler.sample(&store, interval);
+                store.data_mut().profiler = Some(profiler);
+            }
+            Ok(UpdateDeadline::Continue(1))
+        });
+        let engine = engine.clone();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = timer.recv_timeout(interval) {
+                engine.increment_epoch();
+            }
+        });
+    } else if let Some(timeout) = limits.timeout {
+        store.set_epoch_deadline(1);
+        let engine = engine.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = timer.recv_timeout(timeout) {
+                engine.increment_epoch();
+            }
+        });
+    }
+
+    let result = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.get_typed_func::<(), ()>(&mut store, "_start"))
+        .and_then(|start| start.call(&mut store, ()));
+    if let (Some(profile), Some(profiler)) = (run.profile, store.data_mut().profiler.take()) {
+        let file = File::create(profile.path)
+            .with_context(|| format!("failed to create `{}`", profile.path.display()))?;
+        profiler.finish(BufWriter::new(file))?;
+    }
+    let e = match result {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    if let Some(I32Exit(code)) = e.downcast_ref::<I32Exit>() {
+        return match code {
+            0 => Ok(()),
+            code => Err(GuestExit(*code).into()),
+        };
+    }
+    let limit = match e.downcast_ref::<Trap>() {
+        Some(Trap::Interrupt) => limits.timeout.map(Limit::Timeout),
+        Some(Trap::OutOfFuel) => limits.fuel.map(Limit::Fuel),
+        _ if e
+            .root_cause()
+            .to_string()
+            .starts_with("forcing trap when growing memory") =>
+        {
+            limits.max_memory.map(Limit::Memory)
+        }
+        _ => None,
+    };
+    if let Some(limit) = limit {
+        return Err(run.exceeded(limit).into());
+    }
+    let e = match e.downcast_ref::<WasmBacktrace>() {
+        Some(trace) => {
+            let mut rewritten = Vec::new();
+            let wasm = Path::new(run.wasm);
+            backtrace::filter(trace.to_string().as_bytes(), &mut rewritten, wasm)?;
+            let rewritten = String::from_utf8_lossy(&rewritten).trim_end().to_string();
+            anyhow!("{}", e.root_cause()).context(rewritten)
+        }
+        None => e,
+    };
+    Err(e.context(format!("failed to run `{}`", run.wasm)))
+}
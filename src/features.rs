@@ -0,0 +1,182 @@
+use crate::utils::read_uleb128;
+use std::collections::BTreeSet;
+use walrus::{Module, RawCustomSection, ValType};
+
+/// LLVM's names for WebAssembly features, as found in the `target_features`
+/// custom section, paired with the `wasm-opt` flag enabling that feature.
+///
+/// Features without an equivalent in the `wasm-opt` we download, such as
+/// `bulk-memory-opt` which is a subset of `bulk-memory`, are left out.
+const FEATURES: &[(&str, &str)] = &[
+    ("atomics", "--enable-threads"),
+    ("bulk-memory", "--enable-bulk-memory"),
+    ("exception-handling", "--enable-exception-handling"),
+    ("extended-const", "--enable-extended-const"),
+    ("gc", "--enable-gc"),
+    ("memory64", "--enable-memory64"),
+    ("multimemory", "--enable-multimemory"),
+    ("multivalue", "--enable-multivalue"),
+    ("mutable-globals", "--enable-mutable-globals"),
+    ("nontrapping-fptoint", "--enable-nontrapping-float-to-int"),
+    ("reference-types", "--enable-reference-types"),
+    ("relaxed-simd", "--enable-relaxed-simd"),
+    ("sign-ext", "--enable-sign-ext"),
+    ("simd128", "--enable-simd"),
+    ("tail-call", "--enable-tail-call"),
+];
+
+/// Returns the `wasm-opt` flags enabling each WebAssembly feature that
+/// `module` was compiled with, in a deterministic order.
+///
+/// The linker records the features used in a `target_features` custom section,
+/// which is our primary source of information. Modules without that section
+/// are instead inspected for telltale signs of the most common features.
+pub fn wasm_opt_flags(module: &Module) -> Vec<&'static str> {
+    let mut flags = BTreeSet::new();
+
+    // Rust 1.67+ emits bulk memory instructions
+    flags.insert("--enable-bulk-memory");
+
+    match target_features(module) {
+        Some(features) => {
+            for feature in features {
+                if let Some((_, flag)) = FEATURES.iter().find(|(name, _)| *name == feature) {
+                    flags.insert(*flag);
+                }
+            }
+        }
+        None => {
+            if module.memories.iter().any(|m| m.shared) {
+                flags.insert("--enable-threads");
+            }
+            let types = module
+                .types
+                .iter()
+                .flat_map(|ty| ty.params().iter().chain(ty.results()).copied())
+                .chain(module.locals.iter().map(|local| local.ty()))
+                .chain(module.globals.iter().map(|global| global.ty))
+                .collect::<Vec<_>>();
+            if types.contains(&ValType::V128) {
+                flags.insert("--enable-simd");
+            }
+            if types.contains(&ValType::Externref) || module.tables.iter().count() > 1 {
+                flags.insert("--enable-reference-types");
+            }
+            if module.types.iter().any(|ty| ty.results().len() > 1) {
+                flags.insert("--enable-multivalue");
+            }
+        }
+    }
+
+    flags.into_iter().collect()
+}
+
+/// Parses the features enabled by the `target_features` custom section, if
+/// present.
+///
+/// The section is a vector of entries, each a prefix byte followed by a
+/// feature name. A `+` prefix means the feature is used and `=` means it's
+/// required, while `-` means it's disallowed.
+fn target_features(module: &Module) -> Option<Vec<String>> {
+    let section = module
+        .customs
+        .iter()
+        .find(|(_, section)| section.name() == "target_features")?
+        .1
+        .as_any()
+        .downcast_ref::<RawCustomSection>()?;
+
+    let mut data = &section.data[..];
+    let count = read_uleb128(&mut data)?;
+    let mut features = Vec::new();
+    for _ in 0..count {
+        let (&prefix, rest) = data.split_first()?;
+        data = rest;
+        let len = read_uleb128(&mut data)? as usize;
+        if data.len() < len {
+            return None;
+        }
+        let (name, rest) = data.split_at(len);
+        data = rest;
+        if prefix == b'+' || prefix == b'=' {
+            features.push(String::from_utf8(name.to_vec()).ok()?);
+        }
+    }
+    Some(features)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(data: Vec<u8>) -> Module {
+        let mut module = Module::default();
+        module.customs.add(RawCustomSection {
+            name: "target_features".to_string(),
+            data,
+        });
+        module
+    }
+
+    fn section(features: &[(u8, &str)]) -> Vec<u8> {
+        let mut data = vec![features.len() as u8];
+        for (prefix, name) in features {
+            data.push(*prefix);
+            data.push(name.len() as u8);
+            data.extend(name.as_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn prefixes() {
+        let module = module(section(&[
+            (b'+', "simd128"),
+            (b'-', "atomics"),
+            (b'=', "mutable-globals"),
+            (b'+', "unknown"),
+        ]));
+        assert_eq!(
+            target_features(&module).unwrap(),
+            ["simd128", "mutable-globals", "unknown"]
+        );
+        assert_eq!(
+            wasm_opt_flags(&module),
+            [
+                "--enable-bulk-memory",
+                "--enable-mutable-globals",
+                "--enable-simd"
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        let mut data = section(&[(b'+', "simd128")]);
+        for len in 0..data.len() {
+            assert!(target_features(&module(data[..len].to_vec())).is_none());
+        }
+        // More entries than there are.
+        data[0] = 2;
+        assert!(target_features(&module(data)).is_none());
+        // A name that isn't UTF-8.
+        assert!(target_features(&module(vec![1, b'+', 1, 0xff])).is_none());
+        assert_eq!(
+            target_features(&module(vec![0])).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn without_section() {
+        let mut module = Module::default();
+        assert!(target_features(&module).is_none());
+        assert_eq!(wasm_opt_flags(&module), ["--enable-bulk-memory"]);
+
+        module.memories.add_local(true, 1, Some(1));
+        assert_eq!(
+            wasm_opt_flags(&module),
+            ["--enable-bulk-memory", "--enable-threads"]
+        );
+    }
+}
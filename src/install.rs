@@ -0,0 +1,480 @@
+use crate::args::{self, CargoArgs};
+use crate::config::Config;
+use crate::internal;
+use crate::runtime::{Limits, Run, Runner};
+use crate::target::Target;
+use crate::utils;
+use crate::{CargoBuild, CargoEvent, Wasm};
+use anyhow::{anyhow, bail, Context, Result};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The options of `cargo wasi install` and `uninstall`, which are our own
+/// rather than Cargo's.
+pub struct Options {
+    root: Option<PathBuf>,
+    /// Whether `--list` was passed, to print the installed packages.
+    pub list: bool,
+}
+
+impl Options {
+    /// Takes our own options out of `args`, returning the rest, which are
+    /// passed to `cargo install` or name the packages to uninstall.
+    pub fn parse(args: Vec<OsString>) -> Result<(Options, Vec<OsString>)> {
+        let mut options = Options {
+            root: None,
+            list: false,
+        };
+        let (own, rest) = args::split_options(args, &["--list"], &["--root"])?;
+        for (flag, value) in own {
+            match (flag, value) {
+                ("--root", Some(root)) => options.root = Some(root.into()),
+                _ => options.list = true,
+            }
+        }
+        Ok((options, rest))
+    }
+
+    /// The directory whose `bin` directory packages are installed into,
+    /// which is `--root` if given and `$CARGO_HOME/wasi` otherwise.
+    ///
+    /// This is always absolute, as wrappers refer to the wasm files in it
+    /// from wherever they're run.
+    pub fn root(&self) -> Result<PathBuf> {
+        let root = match &self.root {
+            Some(root) => root.clone(),
+            None => match env::var_os("CARGO_HOME") {
+                Some(home) => PathBuf::from(home).join("wasi"),
+                None => match dirs::home_dir() {
+                    Some(home) => home.join(".cargo").join("wasi"),
+                    None => bail!("failed to find home directory, is $HOME set?"),
+                },
+            },
+        };
+        Ok(env::current_dir()?.join(root))
+    }
+}
+
+/// Installs the binaries of packages with `cargo install`, post-processing
+/// them like any other release build before copying them into the `bin`
+/// directory of the root along with wrappers that run them.
+pub fn install(args: Vec<OsString>, config: &mut Config) -> Result<()> {
+    let (options, args) = Options::parse(args)?;
+    let root = options.root()?;
+    if options.list {
+        return list(&root, config);
+    }
+    let args = CargoArgs::parse(args)?;
+    config.set_verbose(args.verbose);
+    // The packages may come from anywhere, so there's no workspace to read
+    // `wasi-target` from.
+    let target = Target::select(args.target.as_deref(), None)?;
+
+    // Cargo copies the binaries into a `bin` directory of its own once they
+    // are built, which would race with processing them in place, so it gets
+    // a temporary root that we ignore. Its target directory would otherwise
+    // be temporary as well, and gone before we get to the binaries.
+    let temp = tempfile::tempdir().context("failed to create temporary directory")?;
+    let cargo_root = temp.path().join("root");
+    let mut cargo = Command::new("cargo");
+    cargo.arg("install").arg("--root").arg(&cargo_root);
+    if args.target.is_none() {
+        cargo.arg("--target").arg(target.triple());
+    }
+    let has_target_dir = args.forwarded.iter().any(|arg| {
+        let arg = arg.to_string_lossy();
+        arg == "--target-dir" || arg.starts_with("--target-dir=")
+    });
+    if !has_target_dir {
+        cargo.arg("--target-dir").arg(temp.path().join("target"));
+    }
+    cargo
+        .arg("--message-format")
+        .arg(args.message_format.cargo_arg());
+    cargo.args(&args.forwarded);
+
+    let update_check = internal::UpdateCheck::new(config);
+    crate::install_wasi_target(target, config)?;
+    let json_messages = args.message_format.is_json();
+
+    // Cargo's own messages about installing into the temporary root are
+    // dropped, as we report where the binaries are really installed
+    // ourselves.
+    let hidden = cargo_root.display().to_string();
+    let hide = move |line: &str| {
+        let trimmed = line.trim_start();
+        line.contains(&hidden)
+            || trimmed.starts_with("Installed package ")
+            || trimmed.starts_with("Summary ")
+    };
+    let mut packages = Vec::<(PathBuf, Vec<Wasm>)>::new();
+    crate::execute_cargo(
+        &mut cargo,
+        json_messages,
+        Some(Box::new(hide)),
+        config,
+        |event| {
+            if let CargoEvent::Artifact {
+                wasm,
+                manifest_path: Some(manifest_path),
+                executable: true,
+            } = event
+            {
+                match packages.iter_mut().find(|(m, _)| *m == manifest_path) {
+                    Some((_, wasms)) => wasms.push(wasm),
+                    None => packages.push((manifest_path, vec![wasm])),
+                }
+            }
+            Ok(())
+        },
+    )?;
+    if packages.is_empty() {
+        bail!("`cargo install` didn't build any wasm binaries");
+    }
+
+    let jobserver = crate::jobserver(args.jobs)?;
+    let debug = args.forwarded.iter().any(|arg| arg == "--debug");
+    let profile_name = args.profile_name(if debug { "dev" } else { "release" });
+    for (manifest_path, wasms) in packages {
+        let (name, version, manifest_config) = crate::load_package_config(&manifest_path)?;
+        let bins = wasms
+            .iter()
+            .map(|wasm| {
+                wasm.path
+                    .file_stem()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect::<Vec<_>>();
+        check_conflicts(&name, &bins, &root, config)?;
+
+        // Wrappers run the runtime directly, so the embedded runtime is
+        // replaced by `wasmtime`, and timeouts aren't enforced.
+        let runner_config = &manifest_config.wasi.runner;
+        let runner = Runner::select(target, runner_config.runtime.as_deref())?.external();
+        runner.check_installed()?;
+        // Relative directories are relative to wherever the binary is run.
+        let dirs = runner_config.dirs(Path::new(""));
+        let env = runner_config.env.clone().into_iter().collect::<Vec<_>>();
+        if (!dirs.is_empty() || !env.is_empty()) && !runner.passes_config() {
+            config.info(&format!(
+                "`{}` isn't a runtime known to `cargo wasi`, so the directories \
+                 and env vars in `[package.metadata.wasi.runner]` are not passed to it",
+                runner.name(),
+            ));
+        }
+        if runner_config.inherit_env.is_some() {
+            config.info(
+                "installed binaries don't inherit env vars, so `inherit-env` in \
+                 `[package.metadata.wasi.runner]` is ignored",
+            );
+        }
+        let limits = Limits {
+            timeout: None,
+            ..runner_config.limits()
+        };
+
+        let build = CargoBuild {
+            manifest_config,
+            target,
+            profile_name: profile_name.clone(),
+            json_messages,
+            jobserver: jobserver.clone(),
+            coverage: false,
+            profiling: false,
+        };
+        let mut files = Vec::new();
+        for (wasm, bin) in wasms.iter().zip(&bins) {
+            crate::process_artifact(wasm, &build, config)?;
+            let processed = if build.enable_component() {
+                wasm.path.with_extension("component.wasm")
+            } else {
+                wasm.path.clone()
+            };
+            let dst = bin_path(&root, bin);
+            let dst_str = dst.display().to_string();
+            config.status("Installing", &dst_str);
+            let run = Run {
+                wasm: &dst_str,
+                args: &[],
+                dirs: &dirs,
+                env: &env,
+                limits,
+                profile: None,
+            };
+            files.extend(write_bin(&processed, &dst, &runner.command(&run))?);
+        }
+
+        let executables = bins
+            .iter()
+            .map(|bin| format!("`{}`", bin))
+            .collect::<Vec<_>>();
+        let installed = format!(
+            "package `{} v{}` ({} {})",
+            name,
+            version,
+            if bins.len() == 1 {
+                "executable"
+            } else {
+                "executables"
+            },
+            executables.join(", "),
+        );
+        let package = Package {
+            name,
+            version,
+            root: root.clone(),
+            bins,
+            files,
+        };
+        record(package, config)?;
+        config.status("Installed", &installed);
+    }
+
+    let bin_dir = root.join("bin");
+    let in_path =
+        env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|dir| dir == bin_dir));
+    if !in_path {
+        config.info(&format!(
+            "be sure to add `{}` to your PATH to be able to run the installed binaries",
+            bin_dir.display()
+        ));
+    }
+    update_check.print();
+    Ok(())
+}
+
+/// A package installed by `cargo wasi install`.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    /// The root it was installed into.
+    pub root: PathBuf,
+    /// The names of its binaries.
+    pub bins: Vec<String>,
+    /// Every file written for it, which `uninstall` removes.
+    pub files: Vec<PathBuf>,
+}
+
+/// The list of installed packages, which is kept in the cache so that it
+/// covers every root that packages were installed into.
+#[derive(serde::Serialize, serde::Deserialize, Default)]
+struct Installed {
+    packages: Vec<Package>,
+}
+
+impl Installed {
+    fn load(config: &Config) -> Result<Installed> {
+        let path = config.cache().installed();
+        let json = match fs::read(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Installed::default()),
+            Err(e) => {
+                return Err(e).context(format!("failed to read `{}`", path.display()));
+            }
+        };
+        serde_json::from_slice(&json).context(format!("failed to parse `{}`", path.display()))
+    }
+
+    fn save(&self, config: &Config) -> Result<()> {
+        let path = config.cache().installed();
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json).context(format!("failed to write `{}`", path.display()))
+    }
+
+    fn find(&self, name: &str, root: &Path) -> Option<usize> {
+        self.packages
+            .iter()
+            .position(|package| package.name == name && package.root == root)
+    }
+}
+
+/// Takes the lock on the list of installed packages, which is held while
+/// reading and writing it.
+fn lock(config: &Config) -> Result<impl Drop> {
+    utils::flock(&config.cache().all_versions_root().join("installed-lock"))
+}
+
+/// Checks that none of `bins` belong to another package installed into
+/// `root`, before the binaries of package `name` overwrite them.
+fn check_conflicts(name: &str, bins: &[String], root: &Path, config: &Config) -> Result<()> {
+    let _lock = lock(config)?;
+    let installed = Installed::load(config)?;
+    let others = installed
+        .packages
+        .iter()
+        .filter(|package| package.name != name && package.root == root);
+    for package in others {
+        if let Some(bin) = bins.iter().find(|bin| package.bins.contains(bin)) {
+            bail!(
+                "binary `{}` is already installed as part of `{} v{}`, you'll \
+                 want to `cargo wasi uninstall {}` first",
+                bin,
+                package.name,
+                package.version,
+                package.name,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Records `package` as installed, replacing an earlier installation of it
+/// into the same root and removing the files which that had but it doesn't.
+fn record(package: Package, config: &Config) -> Result<()> {
+    let _lock = lock(config)?;
+    let mut installed = Installed::load(config)?;
+    if let Some(i) = installed.find(&package.name, &package.root) {
+        let old = installed.packages.remove(i);
+        let stale = old
+            .files
+            .iter()
+            .filter(|file| !package.files.contains(file));
+        remove_files(stale, config)?;
+    }
+    installed.packages.push(package);
+    installed.save(config)
+}
+
+/// The path that the binary `name` is installed at in `root`.
+fn bin_path(root: &Path, name: &str) -> PathBuf {
+    root.join("bin").join(format!("{}.wasm", name))
+}
+
+/// Copies the post-processed `wasm` to `dst`, and writes a wrapper next to it
+/// which executes `cmd` with the arguments it's given, returning the paths of
+/// both.
+fn write_bin(wasm: &Path, dst: &Path, cmd: &Command) -> Result<Vec<PathBuf>> {
+    let dir = dst.parent().unwrap();
+    fs::create_dir_all(dir).context(format!("failed to create directory `{}`", dir.display()))?;
+    // The old file may be executing, which a copy over it would corrupt.
+    drop(fs::remove_file(dst));
+    fs::copy(wasm, dst).context(format!("failed to copy `{}`", wasm.display()))?;
+    let wrapper = write_wrapper(&dst.with_extension(""), cmd)?;
+    Ok(vec![dst.to_path_buf(), wrapper])
+}
+
+/// Writes a shell script at `path` which executes `cmd`.
+#[cfg(not(windows))]
+fn write_wrapper(path: &Path, cmd: &Command) -> Result<PathBuf> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut script = String::from("#!/bin/sh\nexec");
+    for word in words(cmd) {
+        script.push(' ');
+        script.push_str(&sh_quote(&word.to_string_lossy()));
+    }
+    script.push_str(" \"$@\"\n");
+    drop(fs::remove_file(path));
+    fs::write(path, script).context(format!("failed to write `{}`", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+        .context(format!("failed to make `{}` executable", path.display()))?;
+    Ok(path.to_path_buf())
+}
+
+/// Writes a batch file at `path` with a `.cmd` extension which executes
+/// `cmd`.
+#[cfg(windows)]
+fn write_wrapper(path: &Path, cmd: &Command) -> Result<PathBuf> {
+    let path = path.with_extension("cmd");
+    let mut script = String::from("@echo off\r\n");
+    for word in words(cmd) {
+        let word = word
+            .to_string_lossy()
+            .replace('"', "\"\"")
+            .replace('%', "%%");
+        script.push_str(&format!("\"{}\" ", word));
+    }
+    script.push_str("%*\r\n");
+    fs::write(&path, script).context(format!("failed to write `{}`", path.display()))?;
+    Ok(path)
+}
+
+fn words(cmd: &Command) -> impl Iterator<Item = &OsStr> {
+    Some(cmd.get_program()).into_iter().chain(cmd.get_args())
+}
+
+/// Quotes `word` for `sh`, leaving it alone if it has nothing special.
+#[cfg(not(windows))]
+fn sh_quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// Prints the packages installed into `root` and their binaries, the same
+/// way as `cargo install --list`.
+fn list(root: &Path, config: &Config) -> Result<()> {
+    let installed = {
+        let _lock = lock(config)?;
+        Installed::load(config)?
+    };
+    for package in installed.packages.iter().filter(|p| p.root == root) {
+        println!("{} v{}:", package.name, package.version);
+        for bin in package.bins.iter() {
+            println!("    {}", bin);
+        }
+    }
+    Ok(())
+}
+
+/// Removes the files of the packages named in `args` that were installed into
+/// the root, and forgets about them.
+pub fn uninstall(args: Vec<OsString>, config: &Config) -> Result<()> {
+    let (options, names) = Options::parse(args)?;
+    if options.list {
+        bail!("`--list` is only supported by `cargo wasi install`");
+    }
+    if names.is_empty() {
+        bail!("`cargo wasi uninstall` must be followed by the packages to uninstall");
+    }
+    let root = options.root()?;
+    let _lock = lock(config)?;
+    let mut installed = Installed::load(config)?;
+    // Nothing is removed unless every package is installed.
+    let mut indices = Vec::new();
+    for name in names.iter() {
+        let name = name.to_string_lossy();
+        let i = installed.find(&name, &root).ok_or_else(|| {
+            anyhow!(
+                "package `{}` is not installed in `{}`",
+                name,
+                root.display()
+            )
+        })?;
+        indices.push(i);
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    for i in indices.into_iter().rev() {
+        let package = installed.packages.remove(i);
+        remove_files(package.files.iter(), config)?;
+        config.status(
+            "Removed",
+            &format!("package `{} v{}`", package.name, package.version),
+        );
+    }
+    installed.save(config)
+}
+
+/// Removes `files`, some of which the user may have removed already.
+fn remove_files<'a>(files: impl Iterator<Item = &'a PathBuf>, config: &Config) -> Result<()> {
+    for file in files {
+        config.status("Removing", &file.display().to_string());
+        match fs::remove_file(file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("failed to remove `{}`", file.display())),
+        }
+    }
+    Ok(())
+}
@@ -10,17 +10,55 @@ use std::time::{Duration, SystemTime};
 
 pub fn main(args: &[OsString], config: &Config) -> Result<()> {
     match args.get(0).and_then(|s| s.to_str()) {
-        Some("clean") => clean(config),
+        Some("clean") => match args.get(1).and_then(|s| s.to_str()) {
+            Some("--wasm-cache") => clean_wasm_cache(config),
+            Some(other) => bail!("unsupported `self clean` option: {}", other),
+            None => clean(config),
+        },
         Some("update-check") => update_check(config),
         Some(other) => bail!("unsupported `self` command: {}", other),
         None => bail!("`self` command must be followed by `clean` or `update-check`"),
     }
 }
 
+/// Removes the cache of all versions of `cargo-wasi`, except for the list of
+/// installed packages, which `cargo wasi uninstall` still needs.
 fn clean(config: &Config) -> Result<()> {
     let path = config.cache().all_versions_root();
     config.status("Removing", &path.display().to_string());
-    if path.exists() {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    let installed = config.cache().installed();
+    for entry in entries {
+        let entry = entry?.path();
+        if entry == installed {
+            continue;
+        }
+        let result = if entry.is_dir() {
+            fs::remove_dir_all(&entry)
+        } else {
+            fs::remove_file(&entry)
+        };
+        result.context(format!("failed to remove `{}`", entry.display()))?;
+    }
+    Ok(())
+}
+
+/// Removes only the cache of post-processed wasm files, for all versions of
+/// `cargo-wasi`, leaving downloaded tools and such in place.
+fn clean_wasm_cache(config: &Config) -> Result<()> {
+    let mut paths = vec![config.cache().wasm_root().to_path_buf()];
+    if let Ok(versions) = fs::read_dir(config.cache().all_versions_root()) {
+        for version in versions {
+            paths.push(version?.path().join("wasm"));
+        }
+    }
+    paths.sort();
+    paths.dedup();
+    for path in paths.iter().filter(|p| p.exists()) {
+        config.status("Removing", &path.display().to_string());
         fs::remove_dir_all(path).context(format!("failed to remove `{}`", path.display()))?;
     }
     Ok(())
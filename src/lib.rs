@@ -1,17 +1,44 @@
+use crate::args::CargoArgs;
 use crate::cache::Cache;
 use crate::config::Config;
+use crate::libtest::Suite;
+use crate::runtime::{Buffer, GuestProfile, Limits, Output, Run, Runner};
+use crate::target::Target;
 use crate::tool_path::ToolPath;
 use crate::utils::CommandExt;
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
-use std::io::{self, Read};
+use std::io::{self, BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+mod args;
+mod backtrace;
+mod bench;
 mod cache;
 mod config;
+mod coverage;
+mod debugger;
+mod debuginfo;
+#[cfg(feature = "embedded-runtime")]
+mod embedded;
+mod features;
+mod install;
 mod internal;
+mod libtest;
+mod nextest;
+mod profiler;
+mod report;
+mod runtime;
+mod target;
 mod tool_path;
 mod utils;
 
@@ -25,6 +52,14 @@ pub fn main() {
         );
         return;
     }
+    if let Ok(shim) = env::var(nextest::SHIM_VAR) {
+        let config = Config::new();
+        if let Err(e) = nextest::exec(&shim) {
+            config.print_error(&utils::hide_normal_process_exit(e, &config));
+            std::process::exit(1);
+        }
+        return;
+    }
 
     let mut config = Config::new();
     match rmain(&mut config) {
@@ -36,14 +71,52 @@ pub fn main() {
     }
 }
 
+/// Cargo subcommands that only need the WASI target passed to them, as they
+/// don't produce wasm files for us to post-process.
+const PASSTHROUGH: &[&str] = &["check", "fix", "clippy", "doc", "rustdoc", "rustc", "tree"];
+
 #[derive(Debug)]
 enum Subcommand {
     Build,
     Run,
     Test,
     Bench,
-    Check,
-    Fix,
+    Nextest,
+    Coverage,
+    Profile,
+    Debug,
+    /// One of the `PASSTHROUGH` subcommands.
+    Passthrough(String),
+}
+
+impl Subcommand {
+    /// The subcommand of Cargo which does the work.
+    fn cargo_command(&self) -> &str {
+        match self {
+            Subcommand::Build => "build",
+            Subcommand::Test => "test",
+            Subcommand::Bench => "bench",
+            Subcommand::Run | Subcommand::Profile | Subcommand::Debug => "run",
+            Subcommand::Nextest | Subcommand::Coverage => "test",
+            Subcommand::Passthrough(name) => name,
+        }
+    }
+
+    /// The profile that Cargo builds with unless another one is requested.
+    fn default_profile(&self) -> &str {
+        match self.cargo_command() {
+            "test" => "test",
+            "bench" => "bench",
+            _ => "dev",
+        }
+    }
+
+    /// Whether the wasm files that Cargo builds are post-processed, which is
+    /// the case for everything built through `build`, `run`, `test` and
+    /// `bench`.
+    fn post_processes(&self) -> bool {
+        matches!(self.cargo_command(), "build" | "run" | "test" | "bench")
+    }
 }
 
 fn rmain(config: &mut Config) -> Result<()> {
@@ -57,8 +130,12 @@ fn rmain(config: &mut Config) -> Result<()> {
         Some("run") => Subcommand::Run,
         Some("test") => Subcommand::Test,
         Some("bench") => Subcommand::Bench,
-        Some("check") => Subcommand::Check,
-        Some("fix") => Subcommand::Fix,
+        Some("nextest") => Subcommand::Nextest,
+        Some("coverage") => Subcommand::Coverage,
+        Some("profile") => Subcommand::Profile,
+        Some("debug") => Subcommand::Debug,
+        Some("install") => return install::install(args.collect(), config),
+        Some("uninstall") => return install::uninstall(args.collect(), config),
         Some("self") => return internal::main(&args.collect::<Vec<_>>(), config),
         Some("version") | Some("-V") | Some("--version") => {
             let git_info = match option_env!("GIT_INFO") {
@@ -68,31 +145,73 @@ fn rmain(config: &mut Config) -> Result<()> {
             println!("cargo-wasi {}{}", env!("CARGO_PKG_VERSION"), git_info);
             std::process::exit(0);
         }
+        Some(name) if PASSTHROUGH.contains(&name) => Subcommand::Passthrough(name.to_string()),
         _ => print_help(),
     };
 
     let mut cargo = Command::new("cargo");
-    cargo.arg(match subcommand {
-        Subcommand::Build => "build",
-        Subcommand::Check => "check",
-        Subcommand::Fix => "fix",
-        Subcommand::Test => "test",
-        Subcommand::Bench => "bench",
-        Subcommand::Run => "run",
-    });
-
-    // TODO: figure out when these flags are already passed to `cargo` and skip
-    // passing them ourselves.
-    cargo.arg("--target").arg("wasm32-wasi");
-    cargo.arg("--message-format").arg("json-render-diagnostics");
-    for arg in args {
-        if let Some(arg) = arg.to_str() {
-            if arg.starts_with("--verbose") || arg.starts_with("-v") {
-                config.set_verbose(true);
-            }
+    cargo.arg(subcommand.cargo_command());
+
+    // We build the tests for `nextest` ourselves so that they're
+    // post-processed, and then leave running them to `cargo nextest`.
+    let mut nextest_args = Vec::new();
+    let mut profile_options = None;
+    let mut debug_options = None;
+    let args = match subcommand {
+        Subcommand::Nextest => {
+            nextest_args = args.collect::<Vec<_>>();
+            cargo.arg("--no-run");
+            CargoArgs::parse(nextest::build_args(
+                nextest_args.get(1..).unwrap_or_default(),
+            ))?
         }
+        Subcommand::Profile => {
+            let (options, args) = profiler::Options::parse(args.collect())?;
+            profile_options = Some(options);
+            CargoArgs::parse(args)?
+        }
+        Subcommand::Debug => {
+            let (options, args) = debugger::Options::parse(args.collect())?;
+            debug_options = Some(options);
+            CargoArgs::parse(args)?
+        }
+        _ => CargoArgs::parse(args)?,
+    };
+    config.set_verbose(args.verbose);
+    let manifest_config = load_manifest_config()?;
+    let target = Target::select(
+        args.target.as_deref(),
+        manifest_config.wasi_target.as_deref(),
+    )?;
 
-        cargo.arg(arg);
+    // A user-supplied `--target` is already in the forwarded arguments, and
+    // the user's `--message-format` is merged with the JSON that we need to
+    // learn about artifacts.
+    if args.target.is_none() {
+        cargo.arg("--target").arg(target.triple());
+    }
+    if subcommand.post_processes() {
+        cargo
+            .arg("--message-format")
+            .arg(args.message_format.cargo_arg());
+    } else if let Some(format) = args.message_format.requested() {
+        cargo.arg("--message-format").arg(format);
+    }
+    cargo.args(&args.forwarded);
+
+    // Everything else is left to Cargo, which only needs the target to be
+    // installed.
+    if !subcommand.post_processes() {
+        let update_check = internal::UpdateCheck::new(config);
+        install_wasi_target(target, config)?;
+        config.verbose(|| config.status("Running", &format!("{:?}", cargo)));
+        let status = cargo
+            .status()
+            .with_context(|| format!("failed to create process {:?}", cargo))?;
+        utils::check_success(&cargo, &status, &[], &[])
+            .map_err(|e| utils::hide_normal_process_exit(e, config))?;
+        update_check.print();
+        return Ok(());
     }
 
     // If Cargo actually executes a wasm file, we don't want it to. We need to
@@ -108,129 +227,408 @@ fn rmain(config: &mut Config) -> Result<()> {
     // execute everything at the end.
     //
     // Also note that we check here before we actually build that a runtime is
-    // present. We first check the CARGO_TARGET_WASM32_WASI_RUNNER environement
-    // variable for a user-supplied runtime (path or executable) and use the
-    // default, namely `wasmtime`, if it is not set.
-    let (wasi_runner, using_default) = env::var("CARGO_TARGET_WASM32_WASI_RUNNER")
-        .map(|runner_override| (runner_override, false))
-        .unwrap_or_else(|_| ("wasmtime".to_string(), true));
-
-    // Treat the wasi_runner variable as an exectable, followed by a whitespace-
-    // separated list of arguments to the executable. This allows the user to
-    // provide arguments which are passed to wasmtime without having to add more
-    // command-line argument parsing to this crate.
-    let (wasi_runner, wasi_runner_extra_args) = {
-        let mut words = wasi_runner.split_whitespace();
-        let runner = words
-            .next()
-            .ok_or_else(|| anyhow!("$CARGO_TARGET_WASM32_WASI_RUNNER must not be empty"))?;
-        let extra_args = words.collect::<Vec<_>>();
-        (runner, extra_args)
-    };
+    // present. We first check the CARGO_TARGET_<TRIPLE>_RUNNER environement
+    // variable for a user-supplied runtime (path or executable) and otherwise
+    // use the runtime configured in `Cargo.toml`, `wasmtime` by default.
+    let mut runner = Runner::select(target, manifest_config.wasi.runner.runtime.as_deref())?;
+    if let Some(options) = &debug_options {
+        runner = runner.debuggable()?;
+        debugger::check_installed(options)?;
+    }
 
-    match subcommand {
-        Subcommand::Run | Subcommand::Bench | Subcommand::Test => {
-            if !using_default {
-                // check if the override is either a valid path or command found on $PATH
-                if !(Path::new(&wasi_runner).exists() || which::which(&wasi_runner).is_ok()) {
-                    bail!(
-                        "failed to find `{}` (specified by $CARGO_TARGET_WASM32_WASI_RUNNER) \
-                         on the filesytem or in $PATH, you'll want to fix the path or unset \
-                         the $CARGO_TARGET_WASM32_WASI_RUNNER environment variable before \
-                         running this command\n",
-                        &wasi_runner
-                    );
-                }
-            } else if which::which(&wasi_runner).is_err() {
-                let mut msg = format!(
-                    "failed to find `{}` in $PATH, you'll want to \
-                     install `{}` before running this command\n",
-                    wasi_runner, wasi_runner
+    // Coverage builds need a nightly toolchain, so we check for that and the
+    // tools that produce reports before spending time on the build.
+    let coverage = match subcommand {
+        Subcommand::Coverage => {
+            let tools = coverage::check_toolchain()?;
+            if !runner.passes_config() {
+                bail!(
+                    "`{}` isn't a runtime known to `cargo wasi`, so it can't \
+                     preopen the directory that coverage profiles are written to",
+                    runner.name(),
                 );
-                // Because we know what runtime is being used here, we can print
-                // out installation information.
-                if cfg!(unix) {
-                    msg.push_str("you can also install through a shell:\n\n");
-                    msg.push_str("\tcurl https://wasmtime.dev/install.sh -sSf | bash\n");
-                } else {
-                    msg.push_str("you can also install through the installer:\n\n");
-                    msg.push_str("\thttps://github.com/bytecodealliance/wasmtime/releases/download/dev/wasmtime-dev-x86_64-windows.msi\n");
-                }
-                bail!("{}", msg);
             }
+            cargo.env("CARGO_ENCODED_RUSTFLAGS", coverage::rustflags());
+            Some(tools)
+        }
+        _ => None,
+    };
+    // Comparing with a baseline that has no results would waste the time
+    // spent running the benches.
+    let baseline = match (&args.compare, &subcommand) {
+        (Some(baseline), Subcommand::Bench) => Some(bench::Baseline::load(
+            baseline,
+            &manifest_config.workspace_root,
+            &manifest_config.target_directory,
+        )?),
+        (Some(_), _) => bail!("`--compare` is only supported by `cargo wasi bench`"),
+        (None, _) => None,
+    };
+    if profile_options.is_some() && !runner.supports_profiling() {
+        bail!(
+            "`{}` doesn't have a guest profiler that `cargo wasi` knows how to \
+             use, `cargo wasi profile` requires `wasmtime` or the embedded runtime",
+            runner.name(),
+        );
+    }
+
+    match subcommand {
+        Subcommand::Run
+        | Subcommand::Bench
+        | Subcommand::Test
+        | Subcommand::Coverage
+        | Subcommand::Profile
+        | Subcommand::Debug => {
+            runner.check_installed()?;
             cargo.env("__CARGO_WASI_RUNNER_SHIM", "1");
-            cargo.env("CARGO_TARGET_WASM32_WASI_RUNNER", env::current_exe()?);
+            cargo.env(target.runner_env_var(), env::current_exe()?);
         }
 
-        Subcommand::Build | Subcommand::Check | Subcommand::Fix => {}
+        Subcommand::Nextest => {
+            runner.check_installed()?;
+            nextest::check_installed()?;
+        }
+
+        Subcommand::Build | Subcommand::Passthrough(_) => {}
     }
 
     let update_check = internal::UpdateCheck::new(config);
-    install_wasi_target(&config)?;
-    let build = execute_cargo(&mut cargo, &config)?;
-    for (wasm, profile, fresh) in build.wasms.iter() {
-        // Cargo will always overwrite our `wasm` above with its own internal
-        // cache. It's internal cache largely uses hard links.
-        //
-        // If `fresh` is *false*, then Cargo just built `wasm` and we need to
-        // process it. If `fresh` is *true*, then we may have previously
-        // processed it. If our previous processing was successful the output
-        // was placed at `*.wasi.wasm`, so we use that to overwrite the
-        // `*.wasm` file. In the process we also create a `*.rustc.wasm` for
-        // debugging.
-        //
-        // Note that we remove files before renaming and such to ensure that
-        // we're not accidentally updating the wrong hard link and such.
-        let temporary_rustc = wasm.with_extension("rustc.wasm");
-        let temporary_wasi = wasm.with_extension("wasi.wasm");
+    install_wasi_target(target, config)?;
+    let build = CargoBuild {
+        manifest_config,
+        target,
+        profile_name: args.profile_name(subcommand.default_profile()),
+        json_messages: args.message_format.is_json(),
+        jobserver: jobserver(args.jobs)?,
+        coverage: coverage.is_some(),
+        profiling: profile_options.is_some(),
+    };
+    let runs = build_artifacts(&mut cargo, &build, config)?;
+
+    let runner_config = &build.manifest_config.wasi.runner;
+    let dirs = runner_config.dirs(&build.manifest_config.workspace_root);
+    let env = runner_config.env();
+    let executes = !runs.is_empty() || matches!(subcommand, Subcommand::Nextest);
+    let has_runner_config = !dirs.is_empty() || !env.is_empty();
+    if has_runner_config && executes && !runner.passes_config() {
+        config.info(&format!(
+            "`{}` isn't a runtime known to `cargo wasi`, so the directories \
+             and env vars in `[package.metadata.wasi.runner]` are not passed to it",
+            runner.name(),
+        ));
+    }
+    let limits = runner_config.limits();
+    let has_limits = limits.max_memory.is_some() || limits.fuel.is_some();
+    if has_limits && executes && !runner.supports_limits() {
+        config.info(&format!(
+            "`{}` can't limit memory or fuel, so `max-memory` and `fuel` in \
+             `[package.metadata.wasi.runner]` are not enforced",
+            runner.name(),
+        ));
+    }
+    // Instrumented wasm files write their profiles to the directory they have
+    // preopened as `coverage::GUEST_DIR`, wherever it is among the others.
+    let coverage_dir = build.manifest_config.target_directory.join("wasi-coverage");
+    let profiles = coverage_dir.join("profraw");
+    let mut dirs = dirs;
+    if coverage.is_some() {
+        drop(fs::remove_dir_all(&profiles));
+        fs::create_dir_all(&profiles).context(format!(
+            "failed to create directory `{}`",
+            profiles.display()
+        ))?;
+        let host = profiles.display().to_string();
+        dirs.push((coverage::GUEST_DIR.to_string(), host));
+    }
+    if let Subcommand::Nextest = subcommand {
+        let shim = nextest::Shim {
+            runner: runner.spec(),
+            dirs,
+            env,
+            limits,
+        };
+        nextest::run(&nextest_args, build.target, &shim, config)
+            .map_err(|e| utils::hide_normal_process_exit(e, config))?;
+        update_check.print();
+        return Ok(());
+    }
 
-        drop(fs::remove_file(&temporary_rustc));
-        fs::rename(wasm, &temporary_rustc)?;
-        if !*fresh || !temporary_wasi.exists() {
-            // If we found `wasm-bindgen` as a dependency when building then
-            // automatically execute the `wasm-bindgen` CLI, otherwise just process
-            // using normal `walrus` commands.
-            let result = match &build.wasm_bindgen {
-                Some(version) => run_wasm_bindgen(
-                    &temporary_wasi,
-                    &temporary_rustc,
-                    profile,
-                    version,
-                    &build,
-                    &config,
-                ),
-                None => process_wasm(&temporary_wasi, &temporary_rustc, profile, &build, &config),
+    if let (Some(options), Some(run)) = (&debug_options, runs.first()) {
+        let run = Run {
+            wasm: &run[0],
+            args: &run[1..],
+            dirs: &dirs,
+            env: &env,
+            limits,
+            profile: None,
+        };
+        let cmd = runner
+            .debug_command(&run)
+            .context("the runner doesn't support debugging")?;
+        debugger::launch(options, &cmd, run.wasm, config)
+            .map_err(|e| utils::hide_normal_process_exit(e, config))?;
+        update_check.print();
+        return Ok(());
+    }
+
+    let report_config = &build.manifest_config.wasi.test_report;
+    let reports = report::Reports::new(
+        report_config.junit.as_deref(),
+        report_config.json.as_deref(),
+        &build.manifest_config.workspace_root,
+    );
+    let runs_tests = matches!(subcommand, Subcommand::Test | Subcommand::Coverage);
+    let isolate_tests =
+        runs_tests && (args.isolate_tests || runner_config.isolate_tests.unwrap_or(false));
+    // Without isolation, reports are read from what libtest prints.
+    let reads_results = runs_tests && !isolate_tests && reports.is_enabled();
+    let test_jobs = match subcommand {
+        Subcommand::Test | Subcommand::Coverage => {
+            args.test_jobs.or(runner_config.test_jobs).unwrap_or(1)
+        }
+        _ => 1,
+    };
+    // Runtimes write Firefox profiles, which we convert to other formats
+    // afterwards.
+    let mut guest_profiles = Vec::new();
+    if let Some(options) = &profile_options {
+        for run in runs.iter() {
+            let output =
+                options.output(Path::new(&run[0]), &build.manifest_config.target_directory);
+            if let Some(parent) = output.parent() {
+                fs::create_dir_all(parent)
+                    .context(format!("failed to create directory `{}`", parent.display()))?;
+            }
+            let firefox = match options.format {
+                profiler::Format::Firefox => output.clone(),
+                profiler::Format::Pprof => output.with_extension("json"),
             };
-            result.with_context(|| {
-                format!("failed to process wasm at `{}`", temporary_rustc.display())
-            })?;
+            drop(fs::remove_file(&firefox));
+            guest_profiles.push((run[0].clone(), firefox, output));
         }
-        drop(fs::remove_file(&wasm));
-        fs::hard_link(&temporary_wasi, &wasm)
-            .or_else(|_| fs::copy(&temporary_wasi, &wasm).map(|_| ()))?;
     }
-
-    for run in build.runs.iter() {
-        config.status("Running", &format!("`{}`", run.join(" ")));
-        let mut cmd = Command::new(&wasi_runner);
-        for extra_arg in wasi_runner_extra_args.iter() {
-            cmd.arg(extra_arg);
+    // Bench results are picked out of the output of each binary as it's
+    // printed.
+    let captures_benches = matches!(subcommand, Subcommand::Bench);
+    let benches = Mutex::new(Vec::new());
+    let execute = |run: &[String], buffer: Option<&mut Buffer>, suites: &mut Vec<Suite>| {
+        let profile = profile_options.as_ref().and_then(|options| {
+            let (_, firefox, _) = guest_profiles.iter().find(|(wasm, ..)| *wasm == run[0])?;
+            Some(GuestProfile {
+                path: firefox,
+                interval: options.interval,
+            })
+        });
+        let run = Run {
+            wasm: &run[0],
+            args: &run[1..],
+            dirs: &dirs,
+            env: &env,
+            limits,
+            profile,
+        };
+        let result = if isolate_tests {
+            libtest::run_isolated(&runner, &run, buffer, suites)
+        } else if reads_results {
+            libtest::run_reported(&runner, &run, buffer, suites)
+        } else {
+            match buffer {
+                Some(buffer) => runner.run_buffered(&run, buffer),
+                None if captures_benches => {
+                    let mut output = Output::default();
+                    let result = runner.run_captured(&run, &mut output);
+                    let binary = libtest::binary_name(run.wasm);
+                    let parsed = bench::parse(&binary, &output.stdout);
+                    benches.lock().unwrap().extend(parsed);
+                    result
+                }
+                None => runner.run(&run),
+            }
+        };
+        result.map_err(|e| utils::hide_normal_process_exit(e, config))
+    };
+    let mut suites = Vec::new();
+    let result = if test_jobs > 1 && runs.len() > 1 {
+        run_concurrently(&runs, test_jobs, &execute, &mut suites, config)
+    } else {
+        run_sequentially(&runs, args.no_fail_fast, &execute, &mut suites, config)
+    };
+    // Failing tests are what reports are for, so write them either way.
+    if runs_tests {
+        reports.write(&suites, config)?;
+    }
+    let benches = benches.into_inner().unwrap();
+    if !benches.is_empty() {
+        let manifest_config = &build.manifest_config;
+        match bench::revision(&manifest_config.workspace_root) {
+            Some(revision) => bench::save(
+                &benches,
+                &revision,
+                &manifest_config.target_directory,
+                config,
+            )?,
+            None => config.info(
+                "the workspace isn't a git repository, so its benchmark \
+                 results aren't saved",
+            ),
         }
-        cmd.arg("--")
-            .args(run.iter())
-            .run()
-            .map_err(|e| utils::hide_normal_process_exit(e, config))?;
+    }
+    if let Some(baseline) = &baseline {
+        baseline.compare(&benches, config);
+    }
+    // A profile of a failing program still shows where its time went.
+    if let Some(options) = &profile_options {
+        for (wasm, firefox, output) in guest_profiles.iter() {
+            // The runtime may have failed before it got to write anything.
+            if !firefox.exists() {
+                continue;
+            }
+            if options.format == profiler::Format::Pprof {
+                profiler::write_pprof(firefox, output, config)?;
+                drop(fs::remove_file(firefox));
+            }
+            config.status(
+                "Profiled",
+                &format!(
+                    "`{}` to `{}`",
+                    artifact_name(Path::new(wasm)),
+                    output.display()
+                ),
+            );
+        }
+    }
+    if let Some(tools) = &coverage {
+        let mut objects = runs
+            .iter()
+            .map(|run| Path::new(&run[0]).with_extension("rustc.wasm"))
+            .collect::<Vec<_>>();
+        objects.sort();
+        objects.dedup();
+        // Covering the code of failing tests is still useful, but the
+        // failure is what we report.
+        let report = coverage::report(tools, &profiles, &objects, &coverage_dir, config);
+        result.and(report)?;
+    } else {
+        result?;
     }
 
     update_check.print();
     Ok(())
 }
 
+/// Executes a run requested by Cargo, printing its output to `buffer` if
+/// given, and adding the results of tests that were run one by one to the
+/// suites.
+type Execute<'a> =
+    dyn Fn(&[String], Option<&mut Buffer>, &mut Vec<Suite>) -> Result<()> + Sync + 'a;
+
+/// Executes `runs` one after another, printing their output as they go, and
+/// stops at the first failure unless `no_fail_fast` is set.
+fn run_sequentially(
+    runs: &[Vec<String>],
+    no_fail_fast: bool,
+    execute: &Execute<'_>,
+    suites: &mut Vec<Suite>,
+    config: &Config,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for run in runs {
+        config.status("Running", &format!("`{}`", run.join(" ")));
+        if let Err(e) = execute(run, None, suites) {
+            if !no_fail_fast {
+                return Err(e);
+            }
+            errors.push(e);
+        }
+    }
+    run_errors(errors, config)
+}
+
+/// Executes `runs` on up to `jobs` threads, buffering the output of each so
+/// that it's printed in one piece once it finishes.
+///
+/// Like Cargo's `--no-fail-fast`, every run is executed even if others fail.
+fn run_concurrently(
+    runs: &[Vec<String>],
+    jobs: usize,
+    execute: &Execute<'_>,
+    suites: &mut Vec<Suite>,
+    config: &Config,
+) -> Result<()> {
+    let next = AtomicUsize::new(0);
+    let print = Mutex::new(());
+    let mut results = thread::scope(|scope| {
+        let workers = (0..jobs.min(runs.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::SeqCst);
+                        let run = match runs.get(i) {
+                            Some(run) => run,
+                            None => break,
+                        };
+                        let mut buffer = Buffer::default();
+                        let mut suites = Vec::new();
+                        let result = execute(run, Some(&mut buffer), &mut suites);
+
+                        let _lock = print.lock().unwrap();
+                        config.status("Running", &format!("`{}`", run.join(" ")));
+                        let mut stdout = io::stdout().lock();
+                        drop(stdout.write_all(&buffer.stdout));
+                        drop(stdout.flush());
+                        drop(io::stderr().write_all(&buffer.stderr));
+                        results.push((i, suites, result));
+                    }
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| match worker.join() {
+                Ok(results) => results,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    // Report in the order Cargo asked for the runs, like it would itself.
+    results.sort_by_key(|(i, ..)| *i);
+    let mut errors = Vec::new();
+    for (_, run_suites, result) in results {
+        suites.extend(run_suites);
+        if let Err(e) = result {
+            errors.push(e);
+        }
+    }
+    run_errors(errors, config)
+}
+
+/// Combines the errors of several runs, printing each one if there's more
+/// than one, the same way `finish_artifacts` does.
+fn run_errors(mut errors: Vec<anyhow::Error>, config: &Config) -> Result<()> {
+    let last = match errors.pop() {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+    if errors.is_empty() {
+        return Err(last);
+    }
+    for e in errors.iter().chain(Some(&last)) {
+        // Failing tests have explained themselves already.
+        if utils::normal_process_exit_code(e).is_none() {
+            config.print_error(e);
+        }
+    }
+    bail!("{} wasm binaries failed", errors.len() + 1)
+}
+
 fn print_help() -> ! {
     println!(
         "\
 cargo-wasi
-Compile and run a Rust crate for the wasm32-wasi target
+Compile and run a Rust crate for WASI targets such as wasm32-wasip1
 
 USAGE:
     cargo wasi build [OPTIONS]
@@ -239,38 +637,57 @@ USAGE:
     cargo wasi bench [OPTIONS]
     cargo wasi check [OPTIONS]
     cargo wasi fix [OPTIONS]
+    cargo wasi clippy [OPTIONS]
+    cargo wasi doc [OPTIONS]
+    cargo wasi rustdoc [OPTIONS]
+    cargo wasi rustc [OPTIONS]
+    cargo wasi tree [OPTIONS]
+    cargo wasi nextest run [OPTIONS]
+    cargo wasi coverage [OPTIONS]
+    cargo wasi debug [--debugger lldb|gdb] [--vscode] [OPTIONS]
+    cargo wasi profile [--format firefox|pprof] [--interval 1ms] [-o PATH] [OPTIONS]
+    cargo wasi install [--root DIR] [OPTIONS] <CRATE>... | --path PATH
+    cargo wasi install --list
+    cargo wasi uninstall [--root DIR] <PACKAGE>...
     cargo wasi self clean
     cargo wasi self update-check
 
 All options accepted are the same as that of the corresponding `cargo`
-subcommands. You can run `cargo wasi build -h` for more information to learn
+subcommands. Only `build`, `run`, `test` and `bench` (and the subcommands
+built on them) post-process wasm files, while the others are passed through
+to Cargo with the WASI target. You can run `cargo wasi build -h` for more information to learn
 about flags that can be passed to `cargo wasi build`, which mirrors the
 `cargo build` command.
+
+The target defaults to `wasm32-wasip1` and can be changed with `--target`, the
+`CARGO_WASI_TARGET` environment variable, or `wasi-target` in the
+`[package.metadata]` section of `Cargo.toml`.
 "
     );
     std::process::exit(0);
 }
 
-/// Installs the `wasm32-wasi` target into our global cache.
-fn install_wasi_target(config: &Config) -> Result<()> {
-    // We'll make a stamp file when we verify that wasm32-wasi is installed to
+/// Installs the `target` standard library for the current toolchain, if it
+/// isn't already.
+fn install_wasi_target(target: Target, config: &Config) -> Result<()> {
+    // We'll make a stamp file when we verify that the target is installed to
     // accelerate future checks. If that file exists, we're good to go.
     //
     // Note that we account for `$RUSTUP_TOOLCHAIN` if it exists to ensure that
     // if you're moving across toolchains we always make sure that wasi is
     // installed.
-    let stamp_name = "wasi-target-installed".to_string()
+    let stamp_name = format!("{}-target-installed", target)
         + &env::var("RUSTUP_TOOLCHAIN").unwrap_or("".to_string());
     config.cache().stamp(stamp_name).ensure(|| {
         // Ok we need to actually check since this is perhaps the first time we've
         // ever checked. Let's ask rustc what its sysroot is and see if it has a
-        // wasm32-wasi folder.
+        // folder for the target.
         let sysroot = Command::new("rustc")
             .arg("--print")
             .arg("sysroot")
             .capture_stdout()?;
         let sysroot = Path::new(sysroot.trim());
-        if sysroot.join("lib/rustlib/wasm32-wasi").exists() {
+        if sysroot.join("lib/rustlib").join(target.triple()).exists() {
             return Ok(());
         }
 
@@ -279,9 +696,10 @@ fn install_wasi_target(config: &Config) -> Result<()> {
         // wasi target, otherwise we delegate to rustup.
         if env::var_os("RUSTUP_TOOLCHAIN").is_none() {
             bail!(
-                "failed to find the `wasm32-wasi` target installed, and rustup \
+                "failed to find the `{0}` target installed, and rustup \
                  is also not detected, you'll need to be sure to install the \
-                 `wasm32-wasi` target before using this command"
+                 `{0}` target before using this command",
+                target,
             );
         }
 
@@ -293,40 +711,238 @@ fn install_wasi_target(config: &Config) -> Result<()> {
         Command::new("rustup")
             .arg("target")
             .arg("add")
-            .arg("wasm32-wasi")
+            .arg(target.triple())
             .run()?;
         Ok(())
     })
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 struct CargoBuild {
-    // The version of `wasm-bindgen` used in this build, if any.
-    wasm_bindgen: Option<String>,
-    // The `*.wasm` artifacts we found during this build, in addition to the
-    // profile that they were built with and whether or not it was `fresh`
-    // during this build.
-    wasms: Vec<(PathBuf, Profile, bool)>,
-    // executed commands as part of the cargo build
-    runs: Vec<Vec<String>>,
     // Configuration we found in the `Cargo.toml` workspace manifest for these
     // builds.
     manifest_config: ManifestConfig,
+    // The target that the build was compiled for.
+    target: Target,
+    // The name of the Cargo profile that the build uses, which Cargo's JSON
+    // messages don't include.
+    profile_name: String,
+    // Whether the user asked for Cargo's JSON messages on stdout, in which
+    // case we forward them and add our own.
+    json_messages: bool,
+    // Shared with Cargo to limit how many wasm artifacts are post-processed
+    // concurrently alongside the rest of the build.
+    jobserver: jobserver::Client,
+    // Whether we're building for `coverage`, with instrumented wasm files
+    // which need code to write their profiles.
+    coverage: bool,
+    // Whether we're building for `profile`, which needs the name section for
+    // the names of frames.
+    profiling: bool,
+}
+
+/// A `*.wasm` artifact produced by Cargo, along with the profile it was built
+/// with.
+#[derive(Debug)]
+struct Wasm {
+    path: PathBuf,
+    package_id: String,
+    profile: Profile,
+    // The version of `wasm-bindgen` used in this build, if any.
+    wasm_bindgen: Option<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 struct Profile {
     opt_level: String,
+    #[serde(deserialize_with = "deserialize_debuginfo")]
     debuginfo: Option<u32>,
     test: bool,
 }
 
-#[derive(serde::Deserialize, Debug, Default)]
+/// Deserializes the `debuginfo` of a profile, which is `None` if no debuginfo
+/// was requested.
+///
+/// Older versions of Cargo report `null` or a number here, while newer ones
+/// report `0` for no debuginfo and may also use names like
+/// `"line-tables-only"`, which we treat as limited debuginfo.
+fn deserialize_debuginfo<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum DebugInfo {
+        Level(u32),
+        Name(String),
+    }
+
+    let debuginfo = serde::Deserialize::deserialize(deserializer)?;
+    Ok(match debuginfo {
+        None | Some(DebugInfo::Level(0)) => None,
+        Some(DebugInfo::Level(n)) => Some(n),
+        Some(DebugInfo::Name(name)) => match name.as_str() {
+            "none" => None,
+            "full" => Some(2),
+            _ => Some(1),
+        },
+    })
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default)]
 #[serde(rename_all = "kebab-case")]
 struct ManifestConfig {
-    wasm_opt: Option<bool>,
+    wasm_opt: Option<WasmOptConfig>,
+    #[serde(default)]
+    wasm_opt_flags: Vec<String>,
     wasm_name_section: Option<bool>,
     wasm_producers_section: Option<bool>,
+    wasm_split_debuginfo: Option<bool>,
+    component: Option<bool>,
+    wizer: Option<WizerConfig>,
+    wasi_target: Option<String>,
+    // Only affects how wasm files are executed, so it's not part of the cache
+    // key for post-processed wasm files.
+    #[serde(default, skip_serializing)]
+    wasi: WasiConfig,
+    // The directory of the `Cargo.toml` this was read from.
+    #[serde(skip)]
+    workspace_root: PathBuf,
+    #[serde(skip)]
+    target_directory: PathBuf,
+}
+
+/// The `[package.metadata.wasi]` table.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct WasiConfig {
+    #[serde(default)]
+    runner: RunnerConfig,
+    #[serde(default)]
+    test_report: TestReportConfig,
+}
+
+/// The `[package.metadata.wasi.test-report]` table, with paths relative to the
+/// workspace root of the reports that `test` writes.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct TestReportConfig {
+    junit: Option<String>,
+    json: Option<String>,
+}
+
+/// The `[package.metadata.wasi.runner]` table, configuring how wasm files
+/// are executed by `run`, `test` and `bench`.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct RunnerConfig {
+    runtime: Option<String>,
+    /// Host directories to preopen under the same path in the guest.
+    #[serde(default)]
+    dirs: Vec<String>,
+    /// Host directories to preopen, keyed by their path in the guest.
+    #[serde(default)]
+    mapdir: BTreeMap<String, String>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    inherit_env: Option<InheritEnv>,
+    /// Whether `test` runs each test in a separate instance.
+    isolate_tests: Option<bool>,
+    /// The number of test binaries that `test` runs at once.
+    test_jobs: Option<usize>,
+    /// Wall-clock limit of each execution, in seconds.
+    timeout: Option<u64>,
+    /// The maximum size of a linear memory, in bytes.
+    max_memory: Option<u64>,
+    fuel: Option<u64>,
+}
+
+/// The `inherit-env` key of `[package.metadata.wasi.runner]`, which is either
+/// whether to inherit all env vars or a list of env vars to inherit.
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+enum InheritEnv {
+    All(bool),
+    Vars(Vec<String>),
+}
+
+impl RunnerConfig {
+    /// Returns the directories to preopen as pairs of the guest and host path,
+    /// where relative host paths are relative to the workspace root.
+    fn dirs(&self, workspace_root: &Path) -> Vec<(String, String)> {
+        let host = |dir: &str| workspace_root.join(dir).display().to_string();
+        let dirs = self.dirs.iter().map(|dir| (dir.clone(), host(dir)));
+        let mapdirs = self
+            .mapdir
+            .iter()
+            .map(|(guest, dir)| (guest.clone(), host(dir)));
+        dirs.chain(mapdirs).collect()
+    }
+
+    /// Returns the env vars to set in the guest, where those inherited from
+    /// our own environment are overridden by ones that are set explicitly.
+    fn env(&self) -> Vec<(String, String)> {
+        let mut env = match &self.inherit_env {
+            None | Some(InheritEnv::All(false)) => BTreeMap::new(),
+            Some(InheritEnv::All(true)) => env::vars().collect(),
+            Some(InheritEnv::Vars(vars)) => vars
+                .iter()
+                .filter_map(|var| Some((var.clone(), env::var(var).ok()?)))
+                .collect(),
+        };
+        env.extend(self.env.clone());
+        env.into_iter().collect()
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            timeout: self.timeout.map(Duration::from_secs),
+            max_memory: self.max_memory,
+            fuel: self.fuel,
+        }
+    }
+}
+
+/// The `wasm-opt` key of `[package.metadata]`, which either enables or
+/// disables `wasm-opt` outright or configures it per profile, for example in
+/// `[package.metadata.wasm-opt.release]`.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[serde(untagged)]
+enum WasmOptConfig {
+    Enabled(bool),
+    Profiles(BTreeMap<String, WasmOptProfile>),
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct WasmOptProfile {
+    #[serde(default)]
+    flags: Vec<String>,
+}
+
+/// The `wizer` key of `[package.metadata]`, which either enables or disables
+/// pre-initialization with `wizer` outright or configures it in
+/// `[package.metadata.wizer]`.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[serde(untagged)]
+enum WizerConfig {
+    Enabled(bool),
+    Options(WizerOptions),
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct WizerOptions {
+    init_func: Option<String>,
+    #[serde(default)]
+    allow_wasi: bool,
+}
+
+impl WizerOptions {
+    /// The name of the exported function that `wizer` runs.
+    fn init_func(&self) -> &str {
+        self.init_func.as_deref().unwrap_or("wizer.initialize")
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -337,79 +953,582 @@ enum CargoMessage {
         package_id: String,
         profile: Profile,
         fresh: bool,
+        #[serde(default)]
+        manifest_path: Option<PathBuf>,
+        // The wasm file of a binary, which is only reported by newer
+        // versions of Cargo.
+        #[serde(default)]
+        executable: Option<PathBuf>,
     },
+    CompilerMessage,
     BuildScriptExecuted,
     RunWithArgs {
         args: Vec<String>,
     },
     BuildFinished,
+    /// Emitted by us, not Cargo, once a wasm artifact has been post-processed
+    /// when the user requested JSON messages.
+    WasiArtifactProcessed {
+        package_id: String,
+        filename: String,
+        rustc_filename: String,
+        fresh: bool,
+    },
 }
 
 impl CargoBuild {
     fn enable_name_section(&self, profile: &Profile) -> bool {
-        profile.debuginfo.is_some() || self.manifest_config.wasm_name_section.unwrap_or(true)
+        profile.debuginfo.is_some()
+            || self.profiling
+            || self.manifest_config.wasm_name_section.unwrap_or(true)
     }
 
     fn enable_producers_section(&self, profile: &Profile) -> bool {
         profile.debuginfo.is_some() || self.manifest_config.wasm_producers_section.unwrap_or(true)
     }
+
+    /// Whether the DWARF of `wasm` is moved into a separate `*.debug.wasm`
+    /// file, which also allows `wasm-opt` to run over it.
+    ///
+    /// This only applies to modules we process with `walrus` ourselves.
+    fn split_debuginfo(&self, wasm: &Wasm) -> bool {
+        wasm.profile.debuginfo.is_some()
+            && wasm.wasm_bindgen.is_none()
+            && !self.target.emits_component()
+            && self.manifest_config.wasm_split_debuginfo.unwrap_or(false)
+    }
+
+    /// Whether a component is produced next to each core wasm module.
+    fn enable_component(&self) -> bool {
+        !self.target.emits_component() && self.manifest_config.component.unwrap_or(false)
+    }
+
+    /// Returns the options to pre-initialize modules with `wizer`, if enabled.
+    fn wizer(&self) -> Option<WizerOptions> {
+        match &self.manifest_config.wizer {
+            None | Some(WizerConfig::Enabled(false)) => None,
+            Some(WizerConfig::Enabled(true)) => Some(WizerOptions::default()),
+            Some(WizerConfig::Options(options)) => Some(options.clone()),
+        }
+    }
+
+    fn enable_wasm_opt(&self) -> bool {
+        !matches!(
+            self.manifest_config.wasm_opt,
+            Some(WasmOptConfig::Enabled(false))
+        )
+    }
+
+    /// Returns the extra `wasm-opt` flags configured for the build's profile,
+    /// with flags for all profiles first followed by profile-specific ones.
+    fn wasm_opt_flags(&self) -> Vec<&str> {
+        let mut flags = self
+            .manifest_config
+            .wasm_opt_flags
+            .iter()
+            .map(|s| s.as_str())
+            .collect::<Vec<_>>();
+        if let Some(WasmOptConfig::Profiles(profiles)) = &self.manifest_config.wasm_opt {
+            if let Some(profile) = profiles.get(&self.profile_name) {
+                flags.extend(profile.flags.iter().map(|s| s.as_str()));
+            }
+        }
+        flags
+    }
 }
 
-/// Executes the `cargo` command, reading all of the JSON that pops out and
-/// parsing that into a `CargoBuild`.
-fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
-    config.verbose(|| config.status("Running", &format!("{:?}", cargo)));
-    let mut process = cargo
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("failed to spawn `cargo`")?;
-    let mut json = String::new();
-    process
-        .stdout
-        .take()
-        .unwrap()
-        .read_to_string(&mut json)
-        .context("failed to read cargo stdout into a json string")?;
-    let status = process.wait().context("failed to wait on `cargo`")?;
-    utils::check_success(&cargo, &status, &[], &[])
-        .map_err(|e| utils::hide_normal_process_exit(e, config))?;
+/// Returns the jobserver to share between Cargo and our own post-processing.
+///
+/// Like Cargo, we'll join a jobserver that we inherited (for example from
+/// `make`), and otherwise create one sized by `-j`, `$CARGO_BUILD_JOBS` or the
+/// number of CPUs.
+fn jobserver(jobs: Option<usize>) -> Result<jobserver::Client> {
+    // Safety: this is called once, early on, before any file descriptors that
+    // the inherited jobserver may refer to could have been closed.
+    if let Some(client) = unsafe { jobserver::Client::from_env() } {
+        return Ok(client);
+    }
+    let jobs = match jobs {
+        Some(jobs) => jobs,
+        None => match env::var("CARGO_BUILD_JOBS") {
+            Ok(jobs) => jobs
+                .parse()
+                .context(format!("invalid $CARGO_BUILD_JOBS value: `{}`", jobs))?,
+            Err(_) => thread::available_parallelism().map_or(1, |n| n.get()),
+        },
+    };
+    jobserver::Client::new(jobs).context("failed to create jobserver")
+}
 
-    let mut build = CargoBuild::default();
-    for line in json.lines() {
-        if !line.starts_with("{") {
-            println!("{}", line);
-            continue;
+/// Executes the `cargo` command for a build, post-processing the `*.wasm`
+/// artifacts that it produces.
+///
+/// Each `*.wasm` artifact is post-processed on its own thread as soon as Cargo
+/// reports it, so processing overlaps with the rest of the build. Processing
+/// threads acquire a token from `build.jobserver` first, which Cargo shares,
+/// so `-j` bounds rustc and wasm-opt together. Returns the commands that Cargo
+/// asked our runner shim to execute.
+fn build_artifacts(
+    cargo: &mut Command,
+    build: &CargoBuild,
+    config: &Config,
+) -> Result<Vec<Vec<String>>> {
+    build.jobserver.configure(cargo);
+    thread::scope(|scope| {
+        let mut runs = Vec::new();
+        let mut pending = Vec::new();
+        let result = execute_cargo(cargo, build.json_messages, None, config, |event| {
+            match event {
+                CargoEvent::Artifact { wasm, .. } => {
+                    pending.push(scope.spawn(move || {
+                        let result = build
+                            .jobserver
+                            .acquire()
+                            .context("failed to acquire a jobserver token")
+                            .and_then(|_token| process_artifact(&wasm, build, config));
+                        (wasm, result)
+                    }));
+                }
+                CargoEvent::Run(args) => runs.push(args),
+                // Make sure all artifacts are finished, and their messages
+                // printed, before Cargo reports that the build is finished.
+                CargoEvent::BuildFinished => finish_artifacts(&mut pending, build, config)?,
+            }
+            Ok(())
+        });
+        finish_artifacts(&mut pending, build, config)?;
+        result.map(|()| runs)
+    })
+}
+
+/// What `execute_cargo` reports to its caller as Cargo's output streams by.
+enum CargoEvent {
+    /// Cargo produced a `*.wasm` file.
+    Artifact {
+        wasm: Wasm,
+        /// The manifest of the package that it belongs to.
+        manifest_path: Option<PathBuf>,
+        /// Whether it's the executable of a binary, rather than a library.
+        executable: bool,
+    },
+    /// Cargo asked our runner shim to execute a command.
+    Run(Vec<String>),
+    /// Cargo finished the build, which is reported once this event is handled.
+    BuildFinished,
+}
+
+/// Decides which lines of Cargo's stderr `execute_cargo` drops.
+type StderrFilter = Box<dyn Fn(&str) -> bool + Send>;
+
+/// Executes the `cargo` command, streaming the JSON that pops out and passing
+/// what's of interest to `on_event`.
+///
+/// Lines that aren't JSON are printed as they are, as are JSON messages if
+/// `json_messages` is set. Lines of Cargo's stderr are dropped if `hide_stderr`
+/// returns `true` for them, and Cargo's `Finished` line, along with whatever
+/// follows it, is held back until `on_event` has handled the end of the build,
+/// so that it comes after everything reported about the artifacts.
+fn execute_cargo(
+    cargo: &mut Command,
+    json_messages: bool,
+    hide_stderr: Option<StderrFilter>,
+    config: &Config,
+    mut on_event: impl FnMut(CargoEvent) -> Result<()>,
+) -> Result<()> {
+    config.verbose(|| config.status("Running", &format!("{:?}", cargo)));
+    // Cargo doesn't color what it prints into our pipe unless told to.
+    if atty::is(atty::Stream::Stderr) && env::var_os("CARGO_TERM_COLOR").is_none() {
+        cargo.env("CARGO_TERM_COLOR", "always");
+    }
+    cargo.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut process = cargo.spawn().context("failed to spawn `cargo`")?;
+    let stdout = BufReader::new(process.stdout.take().unwrap());
+    let stderr = BufReader::new(process.stderr.take().unwrap());
+    // Signalled, or dropped, once the build's end has been handled.
+    let (release, released) = mpsc::channel::<()>();
+    let stderr = thread::spawn(move || -> io::Result<()> {
+        let mut waiting = true;
+        let mut held: Option<Vec<u8>> = None;
+        for line in stderr.split(b'\n') {
+            let mut line = line?;
+            line.push(b'\n');
+            let text = strip_ansi(&String::from_utf8_lossy(&line));
+            if hide_stderr.as_ref().is_some_and(|hide| hide(&text)) {
+                continue;
+            }
+            if waiting && held.is_none() && text.trim_start().starts_with("Finished ") {
+                held = Some(Vec::new());
+            }
+            let mut out = io::stderr().lock();
+            if let Some(buf) = &mut held {
+                if released.try_recv() == Err(TryRecvError::Empty) {
+                    buf.extend_from_slice(&line);
+                    continue;
+                }
+                out.write_all(buf)?;
+                held = None;
+                waiting = false;
+            }
+            out.write_all(&line)?;
+        }
+        if let Some(buf) = held {
+            let _ = released.recv();
+            io::stderr().write_all(&buf)?;
         }
-        match serde_json::from_str(line) {
-            Ok(CargoMessage::CompilerArtifact {
-                filenames,
-                profile,
-                package_id,
-                fresh,
-            }) => {
-                let mut parts = package_id.split_whitespace();
-                if parts.next() == Some("wasm-bindgen") {
-                    if let Some(version) = parts.next() {
-                        build.wasm_bindgen = Some(version.to_string());
+        Ok(())
+    });
+
+    let read = || -> Result<()> {
+        let mut wasm_bindgen = None;
+        for line in stdout.lines() {
+            let line = line.context("failed to read cargo stdout")?;
+            if !line.starts_with('{') {
+                println!("{}", line);
+                continue;
+            }
+            let msg = serde_json::from_str(&line);
+
+            if let Ok(CargoMessage::BuildFinished) = msg {
+                on_event(CargoEvent::BuildFinished)?;
+                let _ = release.send(());
+            }
+            if json_messages && !matches!(msg, Ok(CargoMessage::RunWithArgs { .. })) {
+                println!("{}", line);
+            }
+
+            match msg {
+                Ok(CargoMessage::CompilerArtifact {
+                    filenames,
+                    profile,
+                    package_id,
+                    manifest_path,
+                    executable,
+                    ..
+                }) => {
+                    if let Some(version) = wasm_bindgen_version(&package_id) {
+                        wasm_bindgen = Some(version);
                     }
-                }
-                for file in filenames {
-                    let file = PathBuf::from(file);
-                    if file.extension().and_then(|s| s.to_str()) == Some("wasm") {
-                        build.wasms.push((file, profile.clone(), fresh));
+                    for file in filenames {
+                        let file = PathBuf::from(file);
+                        if file.extension().and_then(|s| s.to_str()) != Some("wasm") {
+                            continue;
+                        }
+                        let executable = executable.as_ref() == Some(&file);
+                        let wasm = Wasm {
+                            path: file,
+                            package_id: package_id.clone(),
+                            profile: profile.clone(),
+                            wasm_bindgen: wasm_bindgen.clone(),
+                        };
+                        on_event(CargoEvent::Artifact {
+                            wasm,
+                            manifest_path: manifest_path.clone(),
+                            executable,
+                        })?;
                     }
                 }
+                Ok(CargoMessage::RunWithArgs { args }) => on_event(CargoEvent::Run(args))?,
+                Ok(CargoMessage::CompilerMessage) => {}
+                Ok(CargoMessage::BuildScriptExecuted) => {}
+                Ok(CargoMessage::BuildFinished) => {}
+                Ok(CargoMessage::WasiArtifactProcessed { .. }) => {}
+                Err(e) => bail!("failed to parse {}: {}", line, e),
             }
-            Ok(CargoMessage::RunWithArgs { args }) => build.runs.push(args),
-            Ok(CargoMessage::BuildScriptExecuted) => {}
-            Ok(CargoMessage::BuildFinished) => {}
-            Err(e) => bail!("failed to parse {}: {}", line, e),
         }
+        Ok(())
+    };
+
+    let result = read();
+    drop(release);
+    if let Err(e) = result {
+        drop(process.kill());
+        drop(process.wait());
+        return Err(e);
     }
+    let status = process.wait().context("failed to wait on `cargo`")?;
+    stderr
+        .join()
+        .unwrap()
+        .context("failed to read cargo stderr")?;
+    utils::check_success(cargo, &status, &[], &[])
+        .map_err(|e| utils::hide_normal_process_exit(e, config))
+}
 
+/// Removes the escape sequences that color `line`.
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+            continue;
+        }
+        if chars.next() == Some('[') {
+            chars.by_ref().find(|c| ('@'..='~').contains(c));
+        }
+    }
+    stripped
+}
+
+/// Returns the version of `wasm-bindgen` if that's the package of
+/// `package_id`, which subsequent artifacts are then built with.
+fn wasm_bindgen_version(package_id: &str) -> Option<String> {
+    let mut parts = package_id.split_whitespace();
+    if parts.next() != Some("wasm-bindgen") {
+        return None;
+    }
+    parts.next().map(|version| version.to_string())
+}
+
+/// Waits for all `pending` artifacts to finish processing, in the order Cargo
+/// produced them.
+///
+/// A failure to process one artifact doesn't stop the others, and each
+/// failure is reported individually.
+fn finish_artifacts(
+    pending: &mut Vec<thread::ScopedJoinHandle<'_, (Wasm, Result<bool>)>>,
+    build: &CargoBuild,
+    config: &Config,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for handle in pending.drain(..) {
+        let (wasm, result) = match handle.join() {
+            Ok(pair) => pair,
+            Err(panic) => std::panic::resume_unwind(panic),
+        };
+        let reprocessed = match result {
+            Ok(reprocessed) => reprocessed,
+            Err(e) => {
+                errors.push(e);
+                continue;
+            }
+        };
+
+        if build.json_messages {
+            let msg = CargoMessage::WasiArtifactProcessed {
+                package_id: wasm.package_id,
+                filename: wasm.path.display().to_string(),
+                rustc_filename: wasm.path.with_extension("rustc.wasm").display().to_string(),
+                fresh: !reprocessed,
+            };
+            println!("{}", serde_json::to_string(&msg).unwrap());
+        }
+    }
+
+    let last = match errors.pop() {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+    if errors.is_empty() {
+        return Err(last);
+    }
+    for e in errors.iter().chain(Some(&last)) {
+        config.print_error(e);
+    }
+    bail!("failed to process {} wasm artifacts", errors.len() + 1)
+}
+
+/// Post-processes a single `*.wasm` artifact produced by Cargo, returning
+/// whether it actually needed to be reprocessed.
+fn process_artifact(wasm: &Wasm, build: &CargoBuild, config: &Config) -> Result<bool> {
+    // Cargo will always overwrite our `wasm` above with its own internal
+    // cache. It's internal cache largely uses hard links.
+    //
+    // We move Cargo's output to `*.rustc.wasm`, for debugging, and then
+    // produce the post-processed `*.wasi.wasm` which `*.wasm` is linked to.
+    // Post-processed outputs are stored in a content-addressed cache keyed
+    // by everything that went into producing them, so if we've seen this
+    // exact input and configuration before we reuse that output instead,
+    // even if it was produced in another workspace.
+    //
+    // Note that we remove files before renaming and such to ensure that
+    // we're not accidentally updating the wrong hard link and such.
+    let temporary_rustc = wasm.path.with_extension("rustc.wasm");
+    let temporary_wasi = wasm.path.with_extension("wasi.wasm");
+    let debug_wasm = wasm.path.with_extension("debug.wasm");
+    let component_wasm = wasm.path.with_extension("component.wasm");
+    let split_debuginfo = build.split_debuginfo(wasm);
+    let enable_component = build.enable_component();
+
+    // Cargo doesn't rewrite test binaries that are fresh, in which case
+    // `wasm` is still linked to our previous output and the original is
+    // still at `*.rustc.wasm`.
+    let fresh = temporary_rustc.exists()
+        && same_file::is_same_file(&wasm.path, &temporary_wasi).unwrap_or(false);
+    drop(fs::remove_file(&debug_wasm));
+    drop(fs::remove_file(&component_wasm));
+    if !fresh {
+        drop(fs::remove_file(&temporary_rustc));
+        fs::rename(&wasm.path, &temporary_rustc)?;
+    }
+
+    // Besides `*.wasi.wasm` some configurations produce extra files next to
+    // the artifact, which are cached alongside it.
+    let key = artifact_cache_key(&temporary_rustc, wasm, build, config)?;
+    let mut outputs = vec![(config.cache().wasm(&key), &temporary_wasi)];
+    if split_debuginfo {
+        outputs.push((config.cache().wasm(&format!("{}.debug", key)), &debug_wasm));
+    }
+    if enable_component {
+        outputs.push((
+            config.cache().wasm(&format!("{}.component", key)),
+            &component_wasm,
+        ));
+    }
+    let reprocess = outputs.iter().any(|(cached, _)| !cached.exists());
+    if !reprocess {
+        for (cached, path) in outputs.iter() {
+            cached.copy_to(path)?;
+        }
+    } else {
+        // If we found `wasm-bindgen` as a dependency when building then
+        // automatically execute the `wasm-bindgen` CLI, otherwise just process
+        // using normal `walrus` commands.
+        let result = match &wasm.wasm_bindgen {
+            Some(version) => run_wasm_bindgen(
+                &temporary_wasi,
+                &temporary_rustc,
+                &wasm.profile,
+                version,
+                build,
+                config,
+            ),
+            None => process_wasm(
+                &temporary_wasi,
+                &temporary_rustc,
+                if split_debuginfo {
+                    Some(&debug_wasm)
+                } else {
+                    None
+                },
+                &wasm.profile,
+                build,
+                config,
+            ),
+        };
+        let result = result.and_then(|()| match enable_component {
+            true => run_component_encoder(&component_wasm, &temporary_wasi, config),
+            false => Ok(()),
+        });
+        result.with_context(|| {
+            format!("failed to process wasm at `{}`", temporary_rustc.display())
+        })?;
+
+        // Failing to populate the cache only costs us time on a later build.
+        let stored = outputs
+            .iter()
+            .try_for_each(|(cached, path)| cached.store(path));
+        if let Err(e) = stored {
+            config.verbose(|| config.info(&format!("{:#}", e)));
+        }
+    }
+    drop(fs::remove_file(&wasm.path));
+    fs::hard_link(&temporary_wasi, &wasm.path)
+        .or_else(|_| fs::copy(&temporary_wasi, &wasm.path).map(|_| ()))?;
+    Ok(reprocess)
+}
+
+/// Computes the key under which the post-processed output of `rustc_wasm` is
+/// stored in the cache.
+///
+/// This hashes the input wasm file along with everything else that affects
+/// post-processing: the version of `cargo-wasi` itself, which determines the
+/// version of `walrus` too, the target, profile, manifest configuration and
+/// the versions of the tools that will run.
+fn artifact_cache_key(
+    rustc_wasm: &Path,
+    wasm: &Wasm,
+    build: &CargoBuild,
+    config: &Config,
+) -> Result<String> {
+    #[derive(serde::Serialize)]
+    struct Inputs<'a> {
+        cargo_wasi: &'a str,
+        target: &'a str,
+        profile: &'a Profile,
+        profile_name: &'a str,
+        manifest_config: &'a ManifestConfig,
+        wasm_bindgen: Option<String>,
+        wasm_opt: String,
+        wasi_adapter: Option<String>,
+        wizer: Option<String>,
+        coverage: bool,
+        profiling: bool,
+    }
+
+    let wasm_opt = config.get_wasm_opt();
+    let inputs = Inputs {
+        cargo_wasi: env!("CARGO_PKG_VERSION"),
+        target: build.target.triple(),
+        profile: &wasm.profile,
+        profile_name: &build.profile_name,
+        manifest_config: &build.manifest_config,
+        wasm_bindgen: wasm.wasm_bindgen.as_ref().map(|version| {
+            let (path, is_overridden) = config.get_wasm_bindgen(version);
+            tool_version(&path, is_overridden, version)
+        }),
+        wasm_opt: tool_version(
+            wasm_opt.bin_path(),
+            wasm_opt.is_overridden(),
+            WASM_OPT_VERSION,
+        ),
+        wasi_adapter: if build.enable_component() {
+            let versions = ["command", "reactor"]
+                .iter()
+                .map(|kind| {
+                    let adapter = config.get_wasi_adapter(kind, WASI_ADAPTER_VERSION);
+                    let path = adapter.bin_path();
+                    tool_version(path, adapter.is_overridden(), WASI_ADAPTER_VERSION)
+                })
+                .collect::<Vec<_>>();
+            Some(versions.join(", "))
+        } else {
+            None
+        },
+        wizer: build.wizer().map(|_| {
+            let (path, is_overridden) = config.get_wizer(WIZER_VERSION);
+            tool_version(&path, is_overridden, WIZER_VERSION)
+        }),
+        coverage: build.coverage,
+        profiling: build.profiling,
+    };
+
+    let input = fs::read(rustc_wasm)
+        .with_context(|| format!("failed to read `{}`", rustc_wasm.display()))?;
+    let mut hasher = Sha256::new();
+    hasher.update(&input);
+    hasher.update(serde_json::to_vec(&inputs)?);
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Describes the version of a tool for cache keys.
+///
+/// Tools that we download are identified by the version we download, but an
+/// overridden tool could be anything, so it's identified by its path and
+/// modification time instead.
+fn tool_version(path: &Path, is_overridden: bool, version: &str) -> String {
+    if !is_overridden {
+        return version.to_string();
+    }
+    let modified = which::which(path)
+        .unwrap_or_else(|_| path.to_path_buf())
+        .metadata()
+        .ok()
+        .and_then(|metadata| metadata.modified().ok());
+    format!("{} {:?}", path.display(), modified)
+}
+
+/// Reads the `[package.metadata]` configuration out of the workspace's root
+/// `Cargo.toml`.
+fn load_manifest_config() -> Result<ManifestConfig> {
     #[derive(serde::Deserialize)]
     struct CargoMetadata {
         workspace_root: String,
+        target_directory: String,
     }
 
     #[derive(serde::Deserialize)]
@@ -437,11 +1556,52 @@ fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
         manifest.display()
     ))?;
 
-    if let Some(meta) = toml.package.and_then(|p| p.metadata) {
-        build.manifest_config = meta;
+    let mut config = toml.package.and_then(|p| p.metadata).unwrap_or_default();
+    config.workspace_root = PathBuf::from(metadata.workspace_root);
+    config.target_directory = PathBuf::from(metadata.target_directory);
+    Ok(config)
+}
+
+/// Reads the name, version and `[package.metadata]` configuration of the
+/// package whose manifest is at `manifest_path`, which for `install` may be
+/// a package from a registry or git rather than one in the workspace.
+fn load_package_config(manifest_path: &Path) -> Result<(String, String, ManifestConfig)> {
+    #[derive(serde::Deserialize)]
+    struct CargoMetadata {
+        packages: Vec<CargoPackage>,
+        target_directory: String,
     }
 
-    Ok(build)
+    #[derive(serde::Deserialize)]
+    struct CargoPackage {
+        name: String,
+        version: String,
+        manifest_path: PathBuf,
+        metadata: Option<ManifestConfig>,
+    }
+
+    let metadata = Command::new("cargo")
+        .arg("metadata")
+        .arg("--no-deps")
+        .arg("--format-version=1")
+        .arg("--manifest-path")
+        .arg(manifest_path)
+        .capture_stdout()?;
+    let metadata = serde_json::from_str::<CargoMetadata>(&metadata)
+        .context("failed to deserialize `cargo metadata`")?;
+    let package = metadata
+        .packages
+        .into_iter()
+        .find(|package| package.manifest_path == manifest_path)
+        .context(format!(
+            "failed to find the package of `{}` in `cargo metadata`",
+            manifest_path.display()
+        ))?;
+
+    let mut config = package.metadata.unwrap_or_default();
+    config.workspace_root = manifest_path.parent().unwrap().to_path_buf();
+    config.target_directory = PathBuf::from(metadata.target_directory);
+    Ok((package.name, package.version, config))
 }
 
 /// Process a wasm file that doesn't use `wasm-bindgen`, using `walrus` instead.
@@ -450,9 +1610,11 @@ fn execute_cargo(cargo: &mut Command, config: &Config) -> Result<CargoBuild> {
 ///
 /// * Unconditionally demangle all Rust function names.
 /// * Use `profile` to optionally drop debug information
+/// * Move debug information to `debug_wasm`, if provided
 fn process_wasm(
     wasm: &Path,
     temp: &Path,
+    debug_wasm: Option<&Path>,
     profile: &Profile,
     build: &CargoBuild,
     config: &Config,
@@ -461,6 +1623,13 @@ fn process_wasm(
         config.status("Processing", &temp.display().to_string());
     });
 
+    // `walrus` only understands core wasm modules, so components (such as
+    // those produced for `wasm32-wasip2`) are passed through untouched.
+    if build.target.emits_component() {
+        fs::copy(temp, wasm)?;
+        return Ok(());
+    }
+
     let mut module = walrus::ModuleConfig::new()
         // If the `debuginfo` is configured then we leave in the debuginfo
         // sections.
@@ -480,7 +1649,38 @@ fn process_wasm(
         }
     }
 
-    run_wasm_opt(wasm, &module.emit_wasm(), profile, build, config)?;
+    if build.coverage {
+        let name = Path::new(temp.file_stem().unwrap_or_default());
+        let name = name.file_stem().unwrap_or_default().to_string_lossy();
+        coverage::instrument(&mut module, &fs::read(temp)?, &name)?;
+    }
+
+    // Only modules exporting the initialization function are pre-initialized,
+    // which leaves out tests as well as other binaries in the package.
+    let wizer = build.wizer().filter(|options| {
+        !profile.test
+            && module
+                .exports
+                .iter()
+                .any(|export| export.name == options.init_func())
+    });
+    let features = features::wasm_opt_flags(&module);
+    let mut bytes = module.emit_wasm();
+    if let Some(options) = &wizer {
+        bytes = run_wizer(wasm, &bytes, options, &features, config)?;
+    }
+    run_wasm_opt(
+        wasm,
+        &bytes,
+        &features,
+        profile,
+        debug_wasm.is_some(),
+        build,
+        config,
+    )?;
+    if let Some(debug_wasm) = debug_wasm {
+        debuginfo::split(wasm, debug_wasm)?;
+    }
     Ok(())
 }
 
@@ -590,39 +1790,60 @@ fn install_wasm_bindgen(version: &str, path: &Path, config: &Config) -> Result<(
 fn run_wasm_opt(
     wasm: &Path,
     bytes: &[u8],
+    features: &[&str],
     profile: &Profile,
+    split_debuginfo: bool,
     build: &CargoBuild,
     config: &Config,
 ) -> Result<()> {
     // If debuginfo is enabled, automatically disable `wasm-opt`. It will mess
-    // up dwarf debug information currently, so we can't run it.
+    // up dwarf debug information currently, so we can't run it. The exception
+    // is when the DWARF is split out afterwards, in which case `wasm-opt`
+    // keeps it updated with `--debuginfo` and only runs DWARF-preserving
+    // passes, and the split file then matches the optimized code.
     //
     // Additionally if no optimizations are enabled, no need to run `wasm-opt`,
     // we're not optimizing.
-    if profile.debuginfo.is_some() || profile.opt_level == "0" {
+    if (profile.debuginfo.is_some() && !split_debuginfo) || profile.opt_level == "0" {
         fs::write(wasm, bytes)?;
         return Ok(());
     }
 
     // Allow explicitly disabling wasm-opt via `Cargo.toml`.
-    if build.manifest_config.wasm_opt == Some(false) {
+    if !build.enable_wasm_opt() {
         fs::write(wasm, bytes)?;
         return Ok(());
     }
 
-    config.status("Optimizing", "with wasm-opt");
+    config.status(
+        "Optimizing",
+        &format!("{} with wasm-opt", artifact_name(wasm)),
+    );
     let tempdir = tempfile::TempDir::new_in(wasm.parent().unwrap())
         .context("failed to create temporary directory")?;
     let wasm_opt = config.get_wasm_opt();
 
+    // Flags from `Cargo.toml` are appended after the ones we compute, and if
+    // they select an optimization level then it replaces ours.
+    let user_flags = build.wasm_opt_flags();
+    let user_opt_level = user_flags.iter().any(|flag| {
+        matches!(
+            flag.strip_prefix("-O"),
+            Some("" | "0" | "1" | "2" | "3" | "4" | "s" | "z")
+        )
+    });
+
     let input = tempdir.path().join("input.wasm");
-    fs::write(&input, &bytes)?;
+    fs::write(&input, bytes)?;
     let mut cmd = Command::new(wasm_opt.bin_path());
     cmd.arg(&input);
-    cmd.arg(format!("-O{}", profile.opt_level));
+    if !user_opt_level {
+        cmd.arg(format!("-O{}", profile.opt_level));
+    }
     cmd.arg("-o").arg(wasm);
-    // Rust 1.67+ emits bulk memory instructions
-    cmd.arg("--enable-bulk-memory");
+    // Enable exactly the proposals the module was compiled with, otherwise
+    // `wasm-opt` fails to validate it.
+    cmd.args(features);
 
     if build.enable_name_section(profile) {
         cmd.arg("--debuginfo");
@@ -633,6 +1854,7 @@ fn run_wasm_opt(
     if !build.enable_producers_section(profile) {
         cmd.arg("--strip-producers");
     }
+    cmd.args(&user_flags);
 
     run_or_download(
         wasm_opt.bin_path(),
@@ -645,6 +1867,13 @@ fn run_wasm_opt(
     Ok(())
 }
 
+/// Returns the user-facing name of the artifact being written to `wasm`, which
+/// is the `*.wasi.wasm` file that ends up linked to `*.wasm`.
+fn artifact_name(wasm: &Path) -> String {
+    let stem = wasm.file_stem().unwrap_or_default();
+    Path::new(stem).with_extension("wasm").display().to_string()
+}
+
 /// Attempts to execute `cmd` which is executing `requested`.
 ///
 /// If the execution fails because `requested` isn't found *and* `requested` is
@@ -698,8 +1927,147 @@ fn run_or_download(
     cmd.run()
 }
 
+/// Pre-initializes the module `bytes` with `wizer`, returning the snapshot of
+/// the module taken after running its initialization function.
+///
+/// If `$WIZER` is set we'll unconditionally use that, otherwise we'll fall
+/// back to installing `WIZER_VERSION` via `cargo install`.
+fn run_wizer(
+    wasm: &Path,
+    bytes: &[u8],
+    options: &WizerOptions,
+    features: &[&str],
+    config: &Config,
+) -> Result<Vec<u8>> {
+    config.status(
+        "Initializing",
+        &format!("{} with wizer", artifact_name(wasm)),
+    );
+    let tempdir = tempfile::TempDir::new_in(wasm.parent().unwrap())
+        .context("failed to create temporary directory")?;
+    let (wizer, is_overridden) = config.get_wizer(WIZER_VERSION);
+
+    let input = tempdir.path().join("input.wasm");
+    let output = tempdir.path().join("output.wasm");
+    fs::write(&input, bytes)?;
+    let mut cmd = Command::new(&wizer);
+    cmd.arg(&input);
+    cmd.arg("-o").arg(&output);
+    cmd.arg("--init-func").arg(options.init_func());
+    if options.allow_wasi {
+        cmd.arg("--allow-wasi");
+    }
+    // Rust 1.67+ emits bulk memory instructions, which `wizer` needs to be
+    // told about like `wasm-opt`.
+    cmd.arg("--wasm-bulk-memory").arg("true");
+    if features.contains(&"--enable-reference-types") {
+        cmd.arg("--wasm-reference-types").arg("true");
+    }
+
+    run_or_download(wizer.as_ref(), is_overridden, &mut cmd, config, || {
+        install_wizer(wizer.as_ref(), config)
+    })
+    .context("`wizer` failed to execute")?;
+    Ok(fs::read(&output)?)
+}
+
+/// The version of `wizer` that we install.
+const WIZER_VERSION: &str = "10.0.0";
+
+/// Installs the `wizer` executable to `path` with `cargo install`, since no
+/// precompiled binaries are available in a format we can extract.
+fn install_wizer(path: &Path, config: &Config) -> Result<()> {
+    // Note that we modify `PATH` here to suppress the warning that cargo
+    // emits about adding it to PATH.
+    let parent = path.parent().unwrap();
+    let filename = path.file_name().unwrap();
+    config.status("Installing", &format!("wizer v{}", WIZER_VERSION));
+    let path = env::var_os("PATH").unwrap_or_default();
+    let mut path = env::split_paths(&path).collect::<Vec<_>>();
+    path.push(parent.join("bin"));
+    let path = env::join_paths(&path)?;
+    Command::new("cargo")
+        .arg("install")
+        .arg("wizer")
+        .arg("--version")
+        .arg(format!("={}", WIZER_VERSION))
+        .arg("--features")
+        .arg("env_logger structopt")
+        .arg("--root")
+        .arg(parent)
+        .arg("--bin")
+        .arg("wizer")
+        .arg("--locked")
+        .env("PATH", &path)
+        .run()?;
+
+    fs::rename(parent.join("bin").join(filename), parent.join(filename))?;
+    Ok(())
+}
+
+/// Wraps the core wasm module at `temp` into a component written to
+/// `component`, adapting its WASI preview1 imports to WASI preview2.
+///
+/// Modules exporting `_start` are commands and use the command adapter,
+/// anything else is treated as a reactor.
+fn run_component_encoder(component: &Path, temp: &Path, config: &Config) -> Result<()> {
+    let name = component.file_name().unwrap().to_string_lossy();
+    config.status("Componentizing", &format!("{} with the WASI adapter", name));
+    let module = fs::read(temp).with_context(|| format!("failed to read `{}`", temp.display()))?;
+    let is_command = walrus::ModuleConfig::new()
+        .strict_validate(false)
+        .parse(&module)?
+        .exports
+        .iter()
+        .any(|export| export.name == "_start");
+    let kind = if is_command { "command" } else { "reactor" };
+
+    let adapter = config.get_wasi_adapter(kind, WASI_ADAPTER_VERSION);
+    if !adapter.is_overridden() && !adapter.bin_path().exists() {
+        install_wasi_adapter(&adapter, kind, config)?;
+    }
+    let adapter = fs::read(adapter.bin_path()).with_context(|| {
+        format!(
+            "failed to read WASI adapter `{}`",
+            adapter.bin_path().display()
+        )
+    })?;
+
+    let bytes = wit_component::ComponentEncoder::default()
+        .module(&module)?
+        .adapter("wasi_snapshot_preview1", &adapter)?
+        .validate(true)
+        .encode()
+        .context("failed to encode a component")?;
+    fs::write(component, bytes)?;
+    Ok(())
+}
+
+/// The release of Wasmtime that we download the WASI preview1 adapters from.
+const WASI_ADAPTER_VERSION: &str = "v38.0.4";
+
+/// Downloads the `kind` of WASI preview1 adapter, either `command` or
+/// `reactor`, to `path`.
+fn install_wasi_adapter(path: &ToolPath, kind: &str, config: &Config) -> Result<()> {
+    let url = format!(
+        "https://github.com/bytecodealliance/wasmtime/releases/download/{}/\
+         wasi_snapshot_preview1.{}.wasm",
+        WASI_ADAPTER_VERSION, kind,
+    );
+    let (base_path, sub_paths) = path.cache_paths().unwrap();
+    download_file(
+        &url,
+        &format!("WASI {} adapter {}", kind, WASI_ADAPTER_VERSION),
+        &base_path.join(&sub_paths[0]),
+        config,
+    )
+}
+
+/// The release of binaryen that we download `wasm-opt` from.
+const WASM_OPT_VERSION: &str = "version_113";
+
 fn install_wasm_opt(path: &ToolPath, config: &Config) -> Result<()> {
-    let tag = "version_113";
+    let tag = WASM_OPT_VERSION;
     let binaryen_url = |target: &str| {
         let mut url = "https://github.com/WebAssembly/binaryen/releases/download/".to_string();
         url.push_str(tag);
@@ -738,6 +2106,30 @@ fn install_wasm_opt(path: &ToolPath, config: &Config) -> Result<()> {
     )
 }
 
+/// Downloads the single file at `url` to `dst`, unlike `download` which
+/// extracts files from a tarball.
+fn download_file(url: &str, name: &str, dst: &Path, config: &Config) -> Result<()> {
+    let _flock = utils::flock(&config.cache().root().join("downloading"));
+    if dst.exists() {
+        return Ok(());
+    }
+
+    config.status("Downloading", name);
+    config.verbose(|| config.status("Get", url));
+
+    let mut response = utils::get(url)?;
+    (|| -> Result<()> {
+        let dir = dst.parent().unwrap();
+        fs::create_dir_all(dir)
+            .context(format!("failed to create directory `{}`", dir.display()))?;
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        io::copy(&mut response, &mut temp)?;
+        temp.persist(dst)?;
+        Ok(())
+    })()
+    .context(format!("failed to download {}", url))
+}
+
 fn download(
     url: &str,
     name: &str,
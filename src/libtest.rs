@@ -0,0 +1,605 @@
+use crate::runtime::{Buffer, Output, Run, Runner};
+use crate::utils::GuestExit;
+use anyhow::{bail, Result};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Options of libtest which are followed by a value, so that the value isn't
+/// mistaken for a filter.
+const OPTIONS_WITH_VALUES: &[&str] = &[
+    "--color",
+    "--format",
+    "--logfile",
+    "--shuffle-seed",
+    "--skip",
+    "--test-threads",
+    "-Z",
+];
+
+/// A command line argument for a libtest binary, along with its value if it
+/// has one.
+enum Arg<'a> {
+    /// A test name filter.
+    Filter(&'a str),
+    /// `--format`, `--quiet` or `-q`, which we take care of ourselves.
+    Format,
+    Exact,
+    Other(&'a [String]),
+}
+
+fn parse_args(args: &[String]) -> Vec<Arg<'_>> {
+    let mut parsed = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let len = if OPTIONS_WITH_VALUES.contains(&arg) && i + 1 < args.len() {
+            2
+        } else {
+            1
+        };
+        let words = &args[i..i + len];
+        i += len;
+        parsed.push(if arg == "--exact" {
+            Arg::Exact
+        } else if arg == "--quiet" || arg == "-q" || arg.starts_with("--format") {
+            Arg::Format
+        } else if arg.starts_with('-') {
+            Arg::Other(words)
+        } else {
+            Arg::Filter(arg)
+        });
+    }
+    parsed
+}
+
+/// The results of running the tests of one binary.
+pub struct Suite {
+    /// The name of the binary, without the hash Cargo adds to it.
+    pub binary: String,
+    pub tests: Vec<TestResult>,
+    pub filtered_out: usize,
+    pub duration: Duration,
+}
+
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    /// The output of the test's instance, which isn't captured for binaries
+    /// that don't use libtest.
+    pub output: Option<Output>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Ignored,
+    Failed,
+}
+
+impl Suite {
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.tests.iter().filter(|t| t.outcome == outcome).count()
+    }
+}
+
+/// Runs every test of the libtest binary in `run` in a separate instance of
+/// the runtime, printing a summary like libtest's at the end, and adds the
+/// results to `suites`. Everything is printed to `buffer` instead if given.
+///
+/// Since WASI targets can't unwind, a failing test would otherwise abort the
+/// whole binary and take the remaining tests with it. The tests are found
+/// with `--list --format terse` and then each one is run with `--exact`. If
+/// the binary doesn't look like it uses libtest then listing its tests ran it
+/// as usual, and that run is the result of a single test named after the
+/// binary.
+pub fn run_isolated(
+    runner: &Runner,
+    run: &Run<'_>,
+    mut buffer: Option<&mut Buffer>,
+    suites: &mut Vec<Suite>,
+) -> Result<()> {
+    let execute = |buffer: Option<&mut Buffer>| match buffer {
+        Some(buffer) => runner.run_buffered(run, buffer),
+        None => runner.run(run),
+    };
+    let args = parse_args(run.args);
+    if run.args.iter().any(|a| a == "--list") {
+        return execute(buffer);
+    }
+    let binary = binary_name(run.wasm);
+
+    let mut list_args = Vec::new();
+    for arg in args.iter() {
+        match arg {
+            Arg::Filter(filter) => list_args.push(filter.to_string()),
+            Arg::Format => {}
+            Arg::Exact => list_args.push("--exact".to_string()),
+            Arg::Other(words) => list_args.extend(words.iter().cloned()),
+        }
+    }
+    let start = Instant::now();
+    let tests = match list(runner, run, list_args)? {
+        Listing::Tests(tests) => tests,
+        Listing::Ran(output) => {
+            match buffer {
+                Some(buffer) => {
+                    buffer.stdout.extend_from_slice(&output.stdout);
+                    buffer.stderr.extend_from_slice(&output.stderr);
+                }
+                None => {
+                    let mut out = io::stdout().lock();
+                    out.write_all(&output.stdout)?;
+                    out.flush()?;
+                    io::stderr().write_all(&output.stderr)?;
+                }
+            }
+            let result = output.check(run);
+            suites.push(Suite {
+                tests: vec![TestResult {
+                    name: binary.clone(),
+                    outcome: if result.is_ok() {
+                        Outcome::Passed
+                    } else {
+                        Outcome::Failed
+                    },
+                    duration: start.elapsed(),
+                    output: None,
+                }],
+                binary,
+                filtered_out: 0,
+                duration: start.elapsed(),
+            });
+            return result;
+        }
+    };
+    let total = match list(runner, run, Vec::new())? {
+        Listing::Tests(all) => all.len(),
+        Listing::Ran(_) => tests.len(),
+    };
+
+    let (mut stdout, mut stderr): (Box<dyn Write>, Box<dyn Write>) = match &mut buffer {
+        Some(buffer) => (Box::new(&mut buffer.stdout), Box::new(&mut buffer.stderr)),
+        None => (Box::new(io::stdout()), Box::new(io::stderr())),
+    };
+    let start = Instant::now();
+    let nocapture = run.args.iter().any(|a| a == "--nocapture");
+    let mut results = Vec::new();
+    writeln!(stdout)?;
+    writeln!(
+        stdout,
+        "running {} test{}",
+        tests.len(),
+        if tests.len() == 1 { "" } else { "s" }
+    )?;
+    for name in tests {
+        write!(stdout, "test {} ... ", name)?;
+        stdout.flush()?;
+
+        // Output is captured per instance anyway, and this way a panic's
+        // message is printed before the process aborts.
+        let mut test_args = vec![name.clone(), "--exact".to_string()];
+        if !nocapture {
+            test_args.push("--nocapture".to_string());
+        }
+        for arg in args.iter() {
+            if let Arg::Other(words) = arg {
+                test_args.extend(words.iter().cloned());
+            }
+        }
+        let test_start = Instant::now();
+        let output = runner.output(&Run {
+            args: &test_args,
+            ..*run
+        })?;
+        let duration = test_start.elapsed();
+        if nocapture {
+            writeln!(stdout)?;
+            stdout.write_all(&output.stdout)?;
+            stderr.write_all(&output.stderr)?;
+        }
+
+        let outcome = outcome(&output);
+        writeln!(
+            stdout,
+            "{}",
+            match outcome {
+                Outcome::Passed => "ok",
+                Outcome::Ignored => "ignored",
+                Outcome::Failed => "FAILED",
+            }
+        )?;
+        results.push(TestResult {
+            name,
+            outcome,
+            duration,
+            output: Some(output),
+        });
+    }
+    let suite = Suite {
+        binary,
+        filtered_out: total.saturating_sub(results.len()),
+        tests: results,
+        duration: start.elapsed(),
+    };
+
+    let failures = suite
+        .tests
+        .iter()
+        .filter(|t| t.outcome == Outcome::Failed)
+        .collect::<Vec<_>>();
+    if !failures.is_empty() {
+        writeln!(stdout)?;
+        writeln!(stdout, "failures:")?;
+        writeln!(stdout)?;
+        for test in failures.iter() {
+            writeln!(stdout, "---- {} stdout ----", test.name)?;
+            if let Some(output) = &test.output {
+                stdout.write_all(&output.stdout)?;
+                stdout.write_all(&output.stderr)?;
+            }
+            writeln!(stdout)?;
+        }
+        writeln!(stdout)?;
+        writeln!(stdout, "failures:")?;
+        for test in failures.iter() {
+            writeln!(stdout, "    {}", test.name)?;
+        }
+    }
+
+    writeln!(stdout)?;
+    writeln!(
+        stdout,
+        "test result: {}. {} passed; {} failed; {} ignored; 0 measured; {} filtered out; \
+         finished in {:.2}s",
+        if failures.is_empty() { "ok" } else { "FAILED" },
+        suite.count(Outcome::Passed),
+        failures.len(),
+        suite.count(Outcome::Ignored),
+        suite.filtered_out,
+        suite.duration.as_secs_f64(),
+    )?;
+    writeln!(stdout)?;
+
+    let failed = !failures.is_empty();
+    suites.push(suite);
+    if failed {
+        // libtest's exit code for failing tests
+        return Err(GuestExit(101).into());
+    }
+    Ok(())
+}
+
+/// Runs the libtest binary in `run` as usual, and adds the results it prints
+/// to `suites`. Everything is printed to `buffer` instead if given.
+///
+/// The results are read from libtest's default output, which has no durations
+/// of individual tests. Since a failing test aborts the whole binary, its
+/// output is the rest of what the binary printed, and the tests after it
+/// don't show up at all.
+pub fn run_reported(
+    runner: &Runner,
+    run: &Run<'_>,
+    buffer: Option<&mut Buffer>,
+    suites: &mut Vec<Suite>,
+) -> Result<()> {
+    let args = parse_args(run.args);
+    if run.args.iter().any(|a| a == "--list") {
+        return match buffer {
+            Some(buffer) => runner.run_buffered(run, buffer),
+            None => runner.run(run),
+        };
+    }
+    if args.iter().any(|arg| matches!(arg, Arg::Format)) {
+        bail!(
+            "test reports are read from libtest's default output, so `--format` \
+             and `--quiet` can only be used along with them if each test runs in \
+             its own instance with `--isolate-tests`"
+        );
+    }
+
+    let start = Instant::now();
+    let mut output = Output::default();
+    let result = match buffer {
+        Some(buffer) => {
+            output = runner.output(run)?;
+            buffer.stdout.extend_from_slice(&output.stdout);
+            buffer.stderr.extend_from_slice(&output.stderr);
+            output.check(run)
+        }
+        None => runner.run_captured(run, &mut output),
+    };
+    suites.push(parse_results(
+        binary_name(run.wasm),
+        output,
+        start.elapsed(),
+    ));
+    result
+}
+
+/// Reads the results of the tests of `binary` out of what libtest printed to
+/// `output`.
+///
+/// A binary which doesn't look like it uses libtest is a single test named
+/// after the binary.
+fn parse_results(binary: String, output: Output, duration: Duration) -> Suite {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut tests = Vec::new();
+    let mut filtered_out = 0;
+    let mut uses_libtest = false;
+    // A test whose result wasn't printed, as it aborted the binary.
+    let mut unfinished = None;
+    // The captured stdout of the failed test being read, from the failures
+    // section which follows the results.
+    let mut failure: Option<(usize, String)> = None;
+    for line in stdout.lines() {
+        let running = line.strip_prefix("running ");
+        if running.is_some_and(|l| l.ends_with(" test") || l.ends_with(" tests")) {
+            uses_libtest = true;
+        } else if !uses_libtest {
+            continue;
+        } else if let Some(name) = line
+            .strip_prefix("---- ")
+            .and_then(|l| l.strip_suffix(" stdout ----"))
+        {
+            failure = tests
+                .iter()
+                .position(|t: &TestResult| t.name == name)
+                .map(|i| (i, String::new()));
+        } else if line == "failures:" || line.starts_with("test result: ") {
+            if let Some((i, stdout)) = failure.take() {
+                tests[i].output = Some(Output {
+                    stdout: stdout.into_bytes(),
+                    ..Output::default()
+                });
+            }
+            if let Some(summary) = line.strip_prefix("test result: ") {
+                filtered_out = summary
+                    .split(['.', ';'])
+                    .filter_map(|part| part.trim().strip_suffix("filtered out"))
+                    .find_map(|n| n.trim().parse().ok())
+                    .unwrap_or(0);
+            }
+        } else if let Some((_, stdout)) = &mut failure {
+            stdout.push_str(line);
+            stdout.push('\n');
+        } else if let Some((name, result)) = line
+            .strip_prefix("test ")
+            .and_then(|l| l.split_once(" ... "))
+        {
+            let outcome = match result {
+                "ok" => Outcome::Passed,
+                "FAILED" => Outcome::Failed,
+                r if r.starts_with("ignored") => Outcome::Ignored,
+                _ => {
+                    unfinished = Some(name.to_string());
+                    continue;
+                }
+            };
+            unfinished = None;
+            tests.push(TestResult {
+                name: name.to_string(),
+                outcome,
+                duration: Duration::ZERO,
+                output: None,
+            });
+        }
+    }
+
+    if !uses_libtest {
+        tests.push(TestResult {
+            name: binary.clone(),
+            outcome: if output.success {
+                Outcome::Passed
+            } else {
+                Outcome::Failed
+            },
+            duration,
+            output: None,
+        });
+    } else if let Some(name) = unfinished.filter(|_| !output.success) {
+        tests.push(TestResult {
+            name,
+            outcome: Outcome::Failed,
+            duration: Duration::ZERO,
+            output: Some(output),
+        });
+    }
+    Suite {
+        binary,
+        tests,
+        filtered_out,
+        duration,
+    }
+}
+
+/// Returns the name of the binary at `wasm`, without the hash Cargo appends
+/// to the names of test binaries.
+pub fn binary_name(wasm: &str) -> String {
+    let stem = Path::new(wasm)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(wasm);
+    match stem.rsplit_once('-') {
+        Some((name, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            name.to_string()
+        }
+        _ => stem.to_string(),
+    }
+}
+
+/// What listing the tests of a binary turned up.
+enum Listing {
+    /// The names of the tests.
+    Tests(Vec<String>),
+    /// The binary doesn't support listing tests, which means it ran as usual
+    /// instead, with this output.
+    Ran(Output),
+}
+
+/// Lists the names of the tests in `run` which match `args`.
+fn list(runner: &Runner, run: &Run<'_>, mut args: Vec<String>) -> Result<Listing> {
+    args.extend(
+        ["--list", "--format", "terse"]
+            .iter()
+            .map(|s| s.to_string()),
+    );
+    let output = runner.output(&Run {
+        args: &args,
+        ..*run
+    })?;
+    if !output.success {
+        return Ok(Listing::Ran(output));
+    }
+    let mut tests = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if line.is_empty() {
+            continue;
+        }
+        match line
+            .strip_suffix(": test")
+            .or_else(|| line.strip_suffix(": bench"))
+        {
+            Some(name) => tests.push(name.to_string()),
+            None => return Ok(Listing::Ran(output)),
+        }
+    }
+    Ok(Listing::Tests(tests))
+}
+
+/// Determines the outcome of running a single test from the summary libtest
+/// prints, like `test result: ok. 0 passed; 0 failed; 1 ignored; ...`.
+fn outcome(output: &Output) -> Outcome {
+    if !output.success {
+        return Outcome::Failed;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let summary = match stdout.lines().find_map(|l| l.strip_prefix("test result: ")) {
+        Some(summary) => summary,
+        None => return Outcome::Passed,
+    };
+    let nonzero = |what: &str| {
+        summary
+            .split(['.', ';'])
+            .filter_map(|part| part.trim().strip_suffix(what))
+            .any(|n| n.trim().parse::<u32>().is_ok_and(|n| n > 0))
+    };
+    if nonzero("failed") {
+        Outcome::Failed
+    } else if nonzero("ignored") && !nonzero("passed") {
+        Outcome::Ignored
+    } else {
+        Outcome::Passed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(stdout: &str, success: bool) -> Suite {
+        let output = Output {
+            success,
+            stdout: stdout.as_bytes().to_vec(),
+            ..Output::default()
+        };
+        parse_results("foo".to_string(), output, Duration::from_secs(1))
+    }
+
+    fn results(suite: &Suite) -> Vec<(&str, &str)> {
+        let outcome = |t: &TestResult| match t.outcome {
+            Outcome::Passed => "passed",
+            Outcome::Ignored => "ignored",
+            Outcome::Failed => "failed",
+        };
+        suite
+            .tests
+            .iter()
+            .map(|t| (t.name.as_str(), outcome(t)))
+            .collect()
+    }
+
+    #[test]
+    fn results_and_failures() {
+        let suite = parse(
+            "\n\
+             running 4 tests\n\
+             test a ... ok\n\
+             test b ... FAILED\n\
+             test c ... ignored, slow\n\
+             test d::e ... ok\n\
+             \n\
+             failures:\n\
+             \n\
+             ---- b stdout ----\n\
+             printed by b\n\
+             \n\
+             failures:\n    b\n\
+             \n\
+             test result: FAILED. 2 passed; 1 failed; 1 ignored; 0 measured; 3 filtered out; finished in 0.01s\n",
+            false,
+        );
+        assert_eq!(
+            results(&suite),
+            [
+                ("a", "passed"),
+                ("b", "failed"),
+                ("c", "ignored"),
+                ("d::e", "passed")
+            ]
+        );
+        assert_eq!(suite.filtered_out, 3);
+        let stdout = &suite.tests[1].output.as_ref().unwrap().stdout;
+        assert_eq!(String::from_utf8_lossy(stdout), "printed by b\n\n");
+        assert!(suite.tests[0].output.is_none());
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let suite = parse(
+            "running 1 test\n\
+             test a ... ok\n\
+             test without separator\n\
+             test  ... \n\
+             ---- unknown stdout ----\n\
+             test result: ok. 1 passed; x filtered out\n",
+            true,
+        );
+        assert_eq!(results(&suite), [("a", "passed")]);
+        assert_eq!(suite.filtered_out, 0);
+    }
+
+    #[test]
+    fn aborted() {
+        // The test's result is printed after it finishes, so only its name
+        // is there when it aborts the binary.
+        let suite = parse("running 2 tests\ntest a ... ok\ntest b ... ", false);
+        assert_eq!(results(&suite), [("a", "passed"), ("b", "failed")]);
+        assert!(suite.tests[1].output.is_some());
+
+        let suite = parse("running 1 test\ntest b ... ", true);
+        assert_eq!(results(&suite), []);
+    }
+
+    #[test]
+    fn without_libtest() {
+        let suite = parse("test a ... ok\n", false);
+        assert_eq!(results(&suite), [("foo", "failed")]);
+        assert_eq!(suite.tests[0].duration, Duration::from_secs(1));
+        assert_eq!(results(&parse("", true)), [("foo", "passed")]);
+    }
+
+    #[test]
+    fn binary_names() {
+        assert_eq!(
+            binary_name("target/debug/deps/foo-0123456789abcdef.wasm"),
+            "foo"
+        );
+        assert_eq!(binary_name("target/debug/deps/foo-bar.wasm"), "foo-bar");
+        assert_eq!(
+            binary_name("foo-0123456789abcdeg.wasm"),
+            "foo-0123456789abcdeg"
+        );
+    }
+}
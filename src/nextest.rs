@@ -0,0 +1,159 @@
+use crate::config::Config;
+use crate::runtime::{Limits, Run, Runner, RunnerSpec};
+use crate::target::Target;
+use crate::utils;
+use anyhow::{bail, Context, Result};
+use std::env;
+use std::ffi::OsString;
+use std::process::Command;
+
+/// The env var through which `cargo wasi nextest` configures our runner shim,
+/// which tells the shim to execute wasm files rather than report them.
+pub const SHIM_VAR: &str = "__CARGO_WASI_RUNNER_EXEC";
+
+/// Flags of `cargo nextest` that select what to build, which are the same as
+/// those of `cargo test`.
+const BUILD_FLAGS: &[&str] = &[
+    "--lib",
+    "--bins",
+    "--examples",
+    "--tests",
+    "--benches",
+    "--all-targets",
+    "--workspace",
+    "--all",
+    "--all-features",
+    "--no-default-features",
+    "--release",
+    "--ignore-rust-version",
+    "--frozen",
+    "--locked",
+    "--offline",
+];
+
+/// Options of `cargo nextest` that select what to build, along with the
+/// equivalent option of `cargo test`, since nextest's own profiles claim
+/// `--profile` for example.
+const BUILD_OPTIONS: &[(&str, &str)] = &[
+    ("-p", "--package"),
+    ("--package", "--package"),
+    ("--exclude", "--exclude"),
+    ("--bin", "--bin"),
+    ("--example", "--example"),
+    ("--test", "--test"),
+    ("--bench", "--bench"),
+    ("-F", "--features"),
+    ("--features", "--features"),
+    ("--cargo-profile", "--profile"),
+    ("--target", "--target"),
+    ("--target-dir", "--target-dir"),
+    ("--manifest-path", "--manifest-path"),
+    ("--config", "--config"),
+    ("-Z", "-Z"),
+    ("--build-jobs", "--jobs"),
+];
+
+/// How our runner shim executes wasm files on behalf of nextest.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Shim {
+    pub runner: RunnerSpec,
+    pub dirs: Vec<(String, String)>,
+    pub env: Vec<(String, String)>,
+    pub limits: Limits,
+}
+
+/// Picks the arguments for `cargo test --no-run` out of the arguments to
+/// `cargo nextest run`, so that we build and post-process the same test
+/// binaries that nextest will look for.
+pub fn build_args(args: &[OsString]) -> Vec<OsString> {
+    let mut build = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let s = match arg.to_str() {
+            Some(s) => s,
+            None => continue,
+        };
+        if s == "--" {
+            break;
+        }
+        if BUILD_FLAGS.contains(&s) {
+            build.push(arg.clone());
+            continue;
+        }
+        let (flag, value) = match s.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+            _ => (s, None),
+        };
+        let cargo_flag = match BUILD_OPTIONS.iter().find(|(f, _)| *f == flag) {
+            Some((_, cargo_flag)) => cargo_flag,
+            None => continue,
+        };
+        let value = match value {
+            Some(value) => OsString::from(value),
+            None => match args.next() {
+                Some(value) => value.clone(),
+                None => break,
+            },
+        };
+        build.push(cargo_flag.into());
+        build.push(value);
+    }
+    build
+}
+
+/// Checks that `cargo nextest` is installed before we build anything.
+pub fn check_installed() -> Result<()> {
+    if which::which("cargo-nextest").is_err() {
+        bail!(
+            "failed to find `cargo-nextest` in $PATH, you'll want to install \
+             it before running this command:\n\n\
+             \tcargo install cargo-nextest --locked\n"
+        );
+    }
+    Ok(())
+}
+
+/// Runs `cargo nextest` with `args`, which starts with a subcommand like
+/// `run`, with our runner shim set up to execute each test as `shim` says.
+///
+/// The test binaries must already be built and post-processed, so that
+/// nextest's own build finds them fresh.
+pub fn run(args: &[OsString], target: Target, shim: &Shim, config: &Config) -> Result<()> {
+    let (subcommand, args) = match args.split_first() {
+        Some(pair) => pair,
+        None => bail!("`cargo wasi nextest` requires a subcommand such as `run` or `list`"),
+    };
+    let mut cmd = Command::new("cargo");
+    cmd.arg("nextest").arg(subcommand);
+    if !build_args(args).iter().any(|arg| arg == "--target") {
+        cmd.arg("--target").arg(target.triple());
+    }
+    cmd.args(args);
+    cmd.env(target.runner_env_var(), env::current_exe()?);
+    cmd.env(SHIM_VAR, serde_json::to_string(shim)?);
+    config.verbose(|| config.status("Running", &format!("{:?}", cmd)));
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to create process {:?}", cmd))?;
+    utils::check_success(&cmd, &status, &[], &[])
+}
+
+/// Executes a wasm file for nextest, which runs each test by invoking us as
+/// the target runner with the wasm file and its arguments.
+pub fn exec(shim: &str) -> Result<()> {
+    let shim = serde_json::from_str::<Shim>(shim).context("invalid runner shim configuration")?;
+    let runner = Runner::from_spec(shim.runner)?;
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let (wasm, args) = match args.split_first() {
+        Some(pair) => pair,
+        None => bail!("the runner shim requires a wasm file to execute"),
+    };
+    runner.run(&Run {
+        wasm,
+        args,
+        dirs: &shim.dirs,
+        env: &shim.env,
+        limits: shim.limits,
+        profile: None,
+    })
+}
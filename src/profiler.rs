@@ -0,0 +1,291 @@
+use crate::args;
+use crate::config::Config;
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The formats that `profile` writes.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// The JSON of the Firefox profiler, which is what runtimes write.
+    Firefox,
+    /// A gzipped pprof protobuf, as read by `go tool pprof` and others.
+    Pprof,
+}
+
+/// The options of `cargo wasi profile`, which are our own rather than
+/// Cargo's.
+pub struct Options {
+    pub format: Format,
+    pub interval: Duration,
+    output: Option<PathBuf>,
+}
+
+impl Options {
+    /// Takes our own options out of `args`, returning the rest to pass to
+    /// `cargo run`.
+    pub fn parse(args: Vec<OsString>) -> Result<(Options, Vec<OsString>)> {
+        let mut options = Options {
+            format: Format::Firefox,
+            interval: Duration::from_millis(1),
+            output: None,
+        };
+        let (own, rest) =
+            args::split_options(args, &[], &["--format", "--interval", "--output", "-o"])?;
+        for (flag, value) in own {
+            let value = value.unwrap_or_default();
+            match flag {
+                "--format" => {
+                    options.format = match value.to_string_lossy().as_ref() {
+                        "firefox" => Format::Firefox,
+                        "pprof" => Format::Pprof,
+                        value => bail!(
+                            "invalid value for `--format`: `{}`, expected `firefox` or `pprof`",
+                            value
+                        ),
+                    }
+                }
+                "--interval" => options.interval = parse_interval(&value.to_string_lossy())?,
+                _ => options.output = Some(PathBuf::from(value)),
+            }
+        }
+        Ok((options, rest))
+    }
+
+    /// The path to write the profile of `wasm` to, which is in
+    /// `target/wasi-profile` unless `--output` says otherwise.
+    pub fn output(&self, wasm: &Path, target_directory: &Path) -> PathBuf {
+        if let Some(output) = &self.output {
+            return output.clone();
+        }
+        let stem = wasm.file_stem().unwrap_or_default().to_string_lossy();
+        let extension = match self.format {
+            Format::Firefox => "json",
+            Format::Pprof => "pb.gz",
+        };
+        target_directory
+            .join("wasi-profile")
+            .join(format!("{}.{}", stem, extension))
+    }
+}
+
+/// Parses an interval such as `500us`, `2ms` or `1s`, where a plain number is
+/// in milliseconds.
+fn parse_interval(value: &str) -> Result<Duration> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let interval = match (number.parse::<u64>(), unit) {
+        (Ok(n), "us") => Duration::from_micros(n),
+        (Ok(n), "ms") | (Ok(n), "") => Duration::from_millis(n),
+        (Ok(n), "s") => Duration::from_secs(n),
+        _ => bail!("invalid value for `--interval`: `{}`", value),
+    };
+    if interval.is_zero() {
+        bail!("`--interval` must be greater than zero");
+    }
+    Ok(interval)
+}
+
+/// Converts the Firefox profile that a runtime wrote to `firefox` into a
+/// pprof profile at `output`.
+pub fn write_pprof(firefox: &Path, output: &Path, config: &Config) -> Result<()> {
+    let json = fs::read(firefox).context(format!("failed to read `{}`", firefox.display()))?;
+    let profile = serde_json::from_slice::<FirefoxProfile>(&json)
+        .context("failed to parse the profile written by the runtime")?;
+    config.status("Writing", &format!("`{}`", output.display()));
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(&pprof(&profile)?)?;
+    fs::write(output, gz.finish()?).context(format!("failed to write `{}`", output.display()))
+}
+
+/// The parts of the Firefox profiler's processed profile format that we
+/// read, which stores each table as a struct of columns.
+#[derive(serde::Deserialize)]
+struct FirefoxProfile {
+    meta: Meta,
+    threads: Vec<Thread>,
+}
+
+#[derive(serde::Deserialize)]
+struct Meta {
+    /// The time between samples, in milliseconds.
+    interval: f64,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Thread {
+    samples: Samples,
+    stack_table: StackTable,
+    frame_table: FrameTable,
+    func_table: FuncTable,
+    string_array: Vec<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct Samples {
+    stack: Vec<Option<usize>>,
+    weight: Option<Vec<u64>>,
+}
+
+#[derive(serde::Deserialize)]
+struct StackTable {
+    prefix: Vec<Option<usize>>,
+    frame: Vec<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct FrameTable {
+    func: Vec<usize>,
+}
+
+#[derive(serde::Deserialize)]
+struct FuncTable {
+    name: Vec<usize>,
+}
+
+/// Encodes `profile` as a pprof `Profile` message, with a location for each
+/// frame and a function for each of their functions.
+fn pprof(profile: &FirefoxProfile) -> Result<Vec<u8>> {
+    let mut strings = Strings::default();
+    let mut message = Vec::new();
+    let interval = (profile.meta.interval * 1_000_000.0) as u64;
+
+    // sample_type: samples/count and cpu/nanoseconds
+    for (kind, unit) in [("samples", "count"), ("cpu", "nanoseconds")] {
+        let mut value_type = Vec::new();
+        field_varint(&mut value_type, 1, strings.get(kind));
+        field_varint(&mut value_type, 2, strings.get(unit));
+        field_bytes(&mut message, 1, &value_type);
+    }
+
+    // Threads have tables of their own, so their ids are offset.
+    let mut locations = 0;
+    let mut functions = 0;
+    for thread in profile.threads.iter() {
+        for (i, stack) in thread.samples.stack.iter().enumerate() {
+            let mut ids = Vec::new();
+            let mut stack = *stack;
+            while let Some(index) = stack {
+                let frame = thread
+                    .stack_table
+                    .frame
+                    .get(index)
+                    .context("invalid stack")?;
+                ids.push((locations + frame + 1) as u64);
+                stack = *thread
+                    .stack_table
+                    .prefix
+                    .get(index)
+                    .context("invalid stack")?;
+            }
+            let weight = thread
+                .samples
+                .weight
+                .as_ref()
+                .and_then(|w| w.get(i).copied())
+                .unwrap_or(1);
+            let mut sample = Vec::new();
+            field_bytes(&mut sample, 1, &packed(&ids));
+            field_bytes(&mut sample, 2, &packed(&[weight, weight * interval]));
+            field_bytes(&mut message, 2, &sample);
+        }
+
+        for (frame, func) in thread.frame_table.func.iter().enumerate() {
+            let mut line = Vec::new();
+            field_varint(&mut line, 1, (functions + func + 1) as u64);
+            let mut location = Vec::new();
+            field_varint(&mut location, 1, (locations + frame + 1) as u64);
+            field_bytes(&mut location, 4, &line);
+            field_bytes(&mut message, 4, &location);
+        }
+
+        for (func, name) in thread.func_table.name.iter().enumerate() {
+            let name = thread.string_array.get(*name).context("invalid string")?;
+            let name = strings.get(name);
+            let mut function = Vec::new();
+            field_varint(&mut function, 1, (functions + func + 1) as u64);
+            field_varint(&mut function, 2, name);
+            field_varint(&mut function, 3, name);
+            field_bytes(&mut message, 5, &function);
+        }
+
+        locations += thread.frame_table.func.len();
+        functions += thread.func_table.name.len();
+    }
+
+    for string in strings.table.iter() {
+        field_bytes(&mut message, 6, string.as_bytes());
+    }
+    // period_type and period
+    let mut period_type = Vec::new();
+    field_varint(&mut period_type, 1, strings.get("cpu"));
+    field_varint(&mut period_type, 2, strings.get("nanoseconds"));
+    field_bytes(&mut message, 11, &period_type);
+    field_varint(&mut message, 12, interval);
+    Ok(message)
+}
+
+/// The string table of a pprof profile, whose first entry must be empty.
+struct Strings {
+    table: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl Default for Strings {
+    fn default() -> Strings {
+        let mut indices = HashMap::new();
+        indices.insert(String::new(), 0);
+        Strings {
+            table: vec![String::new()],
+            indices,
+        }
+    }
+}
+
+impl Strings {
+    fn get(&mut self, s: &str) -> u64 {
+        if let Some(index) = self.indices.get(s) {
+            return *index;
+        }
+        let index = self.table.len() as u64;
+        self.table.push(s.to_string());
+        self.indices.insert(s.to_string(), index);
+        index
+    }
+}
+
+fn varint(dst: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        dst.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    dst.push(value as u8);
+}
+
+fn field_varint(dst: &mut Vec<u8>, field: u64, value: u64) {
+    varint(dst, field << 3);
+    varint(dst, value);
+}
+
+fn field_bytes(dst: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    varint(dst, (field << 3) | 2);
+    varint(dst, bytes.len() as u64);
+    dst.extend_from_slice(bytes);
+}
+
+fn packed(values: &[u64]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for value in values {
+        varint(&mut bytes, *value);
+    }
+    bytes
+}
@@ -0,0 +1,246 @@
+use crate::config::Config;
+use crate::libtest::{Outcome, Suite, TestResult};
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The reports about test results that `test` writes, if any.
+pub struct Reports {
+    junit: Option<PathBuf>,
+    json: Option<PathBuf>,
+}
+
+impl Reports {
+    /// Paths in the environment take precedence over the ones in
+    /// `[package.metadata.wasi.test-report]`, which are relative to the
+    /// workspace root.
+    pub fn new(junit: Option<&str>, json: Option<&str>, workspace_root: &Path) -> Reports {
+        let path = |var: &str, configured: Option<&str>| match env::var_os(var) {
+            Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+            _ => configured.map(|path| workspace_root.join(path)),
+        };
+        Reports {
+            junit: path("CARGO_WASI_JUNIT_REPORT", junit),
+            json: path("CARGO_WASI_JSON_REPORT", json),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.junit.is_some() || self.json.is_some()
+    }
+
+    pub fn write(&self, suites: &[Suite], config: &Config) -> Result<()> {
+        if let Some(path) = &self.junit {
+            write(path, &junit(suites), config)?;
+        }
+        if let Some(path) = &self.json {
+            write(path, &json(suites), config)?;
+        }
+        Ok(())
+    }
+}
+
+fn write(path: &Path, contents: &str, config: &Config) -> Result<()> {
+    config.status("Writing", &format!("`{}`", path.display()));
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context(format!("failed to create directory `{}`", parent.display()))?;
+    }
+    fs::write(path, contents).context(format!("failed to write `{}`", path.display()))
+}
+
+/// Renders `suites` as JUnit XML, with a `<testsuite>` per binary.
+fn junit(suites: &[Suite]) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<testsuites>\n");
+    for suite in suites {
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" \
+             skipped=\"{}\" time=\"{:.3}\">",
+            escape(&suite.binary),
+            suite.tests.len(),
+            suite.count(Outcome::Failed),
+            suite.count(Outcome::Ignored),
+            suite.duration.as_secs_f64(),
+        );
+        for test in suite.tests.iter() {
+            let _ = write!(
+                xml,
+                "    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"",
+                escape(&suite.binary),
+                escape(&test.name),
+                test.duration.as_secs_f64(),
+            );
+            let output = test.output.as_ref();
+            if test.outcome == Outcome::Passed && output.is_none() {
+                xml.push_str("/>\n");
+                continue;
+            }
+            xml.push_str(">\n");
+            match test.outcome {
+                Outcome::Passed => {}
+                Outcome::Ignored => xml.push_str("      <skipped/>\n"),
+                Outcome::Failed => {
+                    let trap = output.and_then(|o| o.trap.as_deref());
+                    let message = trap
+                        .and_then(|t| t.lines().find(|l| !is_frame(l)))
+                        .unwrap_or("test failed")
+                        .trim();
+                    let _ = writeln!(
+                        xml,
+                        "      <failure message=\"{}\">{}</failure>",
+                        escape(message),
+                        escape(trap.unwrap_or_default()),
+                    );
+                }
+            }
+            if let Some(output) = output {
+                for (tag, bytes) in [
+                    ("system-out", &output.stdout),
+                    ("system-err", &output.stderr),
+                ] {
+                    if !bytes.is_empty() {
+                        let _ = writeln!(
+                            xml,
+                            "      <{tag}>{}</{tag}>",
+                            escape(&String::from_utf8_lossy(bytes)),
+                            tag = tag,
+                        );
+                    }
+                }
+            }
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// Whether `line` of a trap is a frame of its backtrace rather than its
+/// message.
+fn is_frame(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("at ")
+        || line
+            .split_once(':')
+            .is_some_and(|(index, _)| index.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Escapes `s` for use in XML text and attributes, dropping the control
+/// characters and noncharacters that XML can't represent at all.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            '\u{fffe}' | '\u{ffff}' => {}
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders `suites` as the stream of events that libtest prints with
+/// `--format json`, with a `binary` field added to each event.
+fn json(suites: &[Suite]) -> String {
+    let mut events = Vec::new();
+    for suite in suites {
+        let binary = &suite.binary;
+        events.push(json!({
+            "type": "suite",
+            "event": "started",
+            "test_count": suite.tests.len(),
+            "binary": binary,
+        }));
+        for test in suite.tests.iter() {
+            events.push(json!({
+                "type": "test",
+                "event": "started",
+                "name": test.name,
+                "binary": binary,
+            }));
+            events.push(test_event(binary, test));
+        }
+        let failed = suite.count(Outcome::Failed);
+        events.push(json!({
+            "type": "suite",
+            "event": if failed == 0 { "ok" } else { "failed" },
+            "passed": suite.count(Outcome::Passed),
+            "failed": failed,
+            "ignored": suite.count(Outcome::Ignored),
+            "measured": 0,
+            "filtered_out": suite.filtered_out,
+            "exec_time": suite.duration.as_secs_f64(),
+            "binary": binary,
+        }));
+    }
+    events.iter().map(|event| format!("{}\n", event)).collect()
+}
+
+fn test_event(binary: &str, test: &TestResult) -> serde_json::Value {
+    let mut event = json!({
+        "type": "test",
+        "name": test.name,
+        "event": match test.outcome {
+            Outcome::Passed => "ok",
+            Outcome::Ignored => "ignored",
+            Outcome::Failed => "failed",
+        },
+        "exec_time": test.duration.as_secs_f64(),
+        "binary": binary,
+    });
+    if let Some(output) = &test.output {
+        // libtest reports stdout and stderr together, as it captures both.
+        let mut stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        stdout.push_str(&String::from_utf8_lossy(&output.stderr));
+        if !stdout.is_empty() {
+            event["stdout"] = stdout.into();
+        }
+        if let Some(trap) = &output.trap {
+            event["trap"] = trap.as_str().into();
+        }
+    }
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;"
+        );
+        assert_eq!(escape("&amp;"), "&amp;amp;");
+        assert_eq!(escape(""), "");
+    }
+
+    #[test]
+    fn whitespace_and_control_characters() {
+        assert_eq!(escape("a\tb\r\nc"), "a\tb\r\nc");
+        assert_eq!(escape("\x1b[31mred\x1b[0m\0\x7f\u{85}"), "[31mred[0m");
+        assert_eq!(escape("\u{fffe}\u{ffff}\u{fffd}é✓"), "\u{fffd}é✓");
+    }
+
+    #[test]
+    fn frames() {
+        assert!(is_frame("    0: 0x12 - foo"));
+        assert!(is_frame("  at foo (wasm://wasm/foo:wasm-function[3]:0x12)"));
+        assert!(!is_frame("error while executing at wasm backtrace:"));
+        assert!(!is_frame("wasm trap: unreachable"));
+    }
+}
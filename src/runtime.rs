@@ -0,0 +1,917 @@
+use crate::backtrace;
+use crate::target::Target;
+use crate::utils::{self, GuestExit};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A single execution of a wasm file, as requested by Cargo through our
+/// runner shim.
+pub struct Run<'a> {
+    /// The path to the wasm file to execute.
+    pub wasm: &'a str,
+    /// Arguments to pass to the wasm file.
+    pub args: &'a [String],
+    /// Directories to preopen, as pairs of the path in the guest and the path
+    /// on the host.
+    pub dirs: &'a [(String, String)],
+    /// Environment variables to set in the guest.
+    pub env: &'a [(String, String)],
+    pub limits: Limits,
+    /// The profile to write of the guest, if it's being profiled.
+    pub profile: Option<GuestProfile<'a>>,
+}
+
+/// A sampling profile of the guest's own code, which is written in the
+/// format of the Firefox profiler.
+#[derive(Clone, Copy)]
+pub struct GuestProfile<'a> {
+    pub path: &'a Path,
+    /// The time between samples.
+    pub interval: Duration,
+}
+
+/// Limits on the resources of a `Run`, where `None` means unlimited.
+#[derive(Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+pub struct Limits {
+    /// Wall-clock time after which the program is killed.
+    pub timeout: Option<Duration>,
+    /// The maximum size of a linear memory in bytes.
+    pub max_memory: Option<u64>,
+    /// The fuel the program may consume, where a unit is roughly one wasm
+    /// instruction.
+    pub fuel: Option<u64>,
+}
+
+impl Limits {
+    /// Whether exceeding one of these limits makes the program trap, rather
+    /// than being killed.
+    fn trap_when_exceeded(&self) -> bool {
+        self.max_memory.is_some() || self.fuel.is_some()
+    }
+
+    /// Works out which limit, if any, stopped a run that either timed out or
+    /// trapped with `trap`.
+    fn exceeded(&self, timed_out: bool, trap: Option<&str>) -> Option<Limit> {
+        if timed_out {
+            return self.timeout.map(Limit::Timeout);
+        }
+        let trap = trap?;
+        if trap.contains("forcing trap when growing memory") {
+            return self.max_memory.map(Limit::Memory);
+        }
+        if trap.contains("all fuel consumed") {
+            return self.fuel.map(Limit::Fuel);
+        }
+        None
+    }
+}
+
+/// One of the `Limits` of a `Run`, along with its value.
+#[derive(Debug, Clone, Copy)]
+pub enum Limit {
+    Timeout(Duration),
+    Memory(u64),
+    Fuel(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Timeout(timeout) => write!(f, "timeout of {:?}", timeout),
+            Limit::Memory(bytes) => write!(f, "memory limit of {} bytes", bytes),
+            Limit::Fuel(fuel) => write!(f, "fuel limit of {} units", fuel),
+        }
+    }
+}
+
+/// The error of a `Run` that was stopped for exceeding one of its `Limits`.
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub wasm: String,
+    pub limit: Limit,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}` exceeded its {}", self.wasm, self.limit)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// The captured output of a `Run`.
+#[derive(Default)]
+pub struct Output {
+    pub success: bool,
+    /// The exit code, unless the program was killed or trapped in-process.
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+    /// Everything the program and runtime printed to stderr, which includes
+    /// any trap.
+    pub stderr: Vec<u8>,
+    /// The message and symbolicated backtrace of a trap, if there was one.
+    pub trap: Option<String>,
+}
+
+/// Output that is buffered to be printed in one piece, for when several runs
+/// execute at once.
+#[derive(Default)]
+pub struct Buffer {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// A WebAssembly runtime which `cargo wasi` can execute wasm files with.
+///
+/// Every runtime has its own command line conventions, so implementations
+/// translate a `Run` into the appropriate invocation.
+pub trait Runtime {
+    /// The name of the runtime, as used for `runtime` in
+    /// `[package.metadata.wasi.runner]`, which is also the name of its
+    /// executable.
+    fn name(&self) -> &'static str;
+
+    /// Instructions for installing the runtime if it can't be found.
+    fn install_hint(&self) -> String;
+
+    /// Appends the arguments to `cmd`, which executes this runtime with any
+    /// user-specified `extra_args` already added, to perform `run`.
+    fn configure(&self, cmd: &mut Command, run: &Run<'_>);
+
+    /// Whether this is one of the runtimes built into `cargo wasi`, which
+    /// knows how to pass along directories and env vars.
+    fn is_known(&self) -> bool {
+        true
+    }
+
+    /// Whether `configure` passes the memory and fuel `Limits` of a run to the
+    /// runtime. Timeouts are enforced by `cargo wasi` itself.
+    fn supports_limits(&self) -> bool {
+        false
+    }
+
+    /// Whether `configure` makes the runtime write the `GuestProfile` of a run.
+    fn supports_profiling(&self) -> bool {
+        false
+    }
+
+    /// Arguments which make the runtime register the code it compiles, along
+    /// with its DWARF, with native debuggers like lldb and gdb.
+    fn debug_args(&self) -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+struct Wasmtime;
+
+impl Runtime for Wasmtime {
+    fn name(&self) -> &'static str {
+        "wasmtime"
+    }
+
+    fn install_hint(&self) -> String {
+        if cfg!(unix) {
+            "you can also install through a shell:\n\n\
+             \tcurl https://wasmtime.dev/install.sh -sSf | bash\n"
+                .to_string()
+        } else {
+            "you can also install through the installer:\n\n\
+             \thttps://github.com/bytecodealliance/wasmtime/releases/download/dev/wasmtime-dev-x86_64-windows.msi\n"
+                .to_string()
+        }
+    }
+
+    fn supports_limits(&self) -> bool {
+        true
+    }
+
+    fn supports_profiling(&self) -> bool {
+        true
+    }
+
+    fn debug_args(&self) -> Option<&'static [&'static str]> {
+        // Optimizations leave little of the DWARF's variables and lines intact.
+        Some(&["-D", "debug-info", "-O", "opt-level=0"])
+    }
+
+    fn configure(&self, cmd: &mut Command, run: &Run<'_>) {
+        for (guest, host) in run.dirs {
+            cmd.arg("--dir").arg(format!("{}::{}", host, guest));
+        }
+        for (key, value) in run.env {
+            cmd.arg("--env").arg(format!("{}={}", key, value));
+        }
+        if let Some(bytes) = run.limits.max_memory {
+            // Trapping tells running out of memory apart from other failures.
+            cmd.arg("-W").arg(format!("max-memory-size={}", bytes));
+            cmd.arg("-W").arg("trap-on-grow-failure=y");
+        }
+        if let Some(fuel) = run.limits.fuel {
+            cmd.arg("-W").arg(format!("fuel={}", fuel));
+        }
+        if let Some(profile) = run.profile {
+            cmd.arg(format!(
+                "--profile=guest,{},{}us",
+                profile.path.display(),
+                profile.interval.as_micros(),
+            ));
+        }
+        cmd.arg("--").arg(run.wasm).args(run.args);
+    }
+}
+
+struct Wasmer;
+
+impl Runtime for Wasmer {
+    fn name(&self) -> &'static str {
+        "wasmer"
+    }
+
+    fn install_hint(&self) -> String {
+        if cfg!(unix) {
+            "you can also install through a shell:\n\n\
+             \tcurl https://get.wasmer.io -sSfL | sh\n"
+                .to_string()
+        } else {
+            "you can also install through PowerShell:\n\n\
+             \tiwr https://win.wasmer.io -useb | iex\n"
+                .to_string()
+        }
+    }
+
+    fn configure(&self, cmd: &mut Command, run: &Run<'_>) {
+        cmd.arg("run");
+        for (guest, host) in run.dirs {
+            cmd.arg("--mapdir").arg(format!("{}:{}", guest, host));
+        }
+        for (key, value) in run.env {
+            cmd.arg("--env").arg(format!("{}={}", key, value));
+        }
+        cmd.arg(run.wasm).arg("--").args(run.args);
+    }
+}
+
+struct WasmEdge;
+
+impl Runtime for WasmEdge {
+    fn name(&self) -> &'static str {
+        "wasmedge"
+    }
+
+    fn install_hint(&self) -> String {
+        "you can also install through a shell:\n\n\
+         \tcurl -sSf https://raw.githubusercontent.com/WasmEdge/WasmEdge/master/utils/install.sh | bash\n"
+            .to_string()
+    }
+
+    fn configure(&self, cmd: &mut Command, run: &Run<'_>) {
+        for (guest, host) in run.dirs {
+            cmd.arg("--dir").arg(format!("{}:{}", guest, host));
+        }
+        for (key, value) in run.env {
+            cmd.arg("--env").arg(format!("{}={}", key, value));
+        }
+        cmd.arg(run.wasm).args(run.args);
+    }
+}
+
+struct Wazero;
+
+impl Runtime for Wazero {
+    fn name(&self) -> &'static str {
+        "wazero"
+    }
+
+    fn install_hint(&self) -> String {
+        "you can also install through a shell:\n\n\
+         \tcurl https://wazero.io/install.sh | sh\n"
+            .to_string()
+    }
+
+    fn configure(&self, cmd: &mut Command, run: &Run<'_>) {
+        cmd.arg("run");
+        for (guest, host) in run.dirs {
+            cmd.arg(format!("-mount={}:{}", host, guest));
+        }
+        for (key, value) in run.env {
+            cmd.arg(format!("-env={}={}", key, value));
+        }
+        cmd.arg(run.wasm).args(run.args);
+    }
+}
+
+/// Node.js has no command for running WASI programs, so we pass it a small
+/// script using its `wasi` module instead.
+struct Node;
+
+/// Runs a wasm file with Node's `wasi` module. The first argument is a JSON
+/// object with the preopens and environment, followed by the wasm file and its
+/// arguments.
+const NODE_SCRIPT: &str = r#"
+const { readFileSync } = require('fs');
+const { WASI } = require('wasi');
+const [config, wasm, ...args] = process.argv.slice(1);
+const { preopens, env } = JSON.parse(config);
+const wasi = new WASI({
+  version: 'preview1',
+  args: [wasm, ...args],
+  env,
+  preopens,
+  returnOnExit: true,
+});
+const module = new WebAssembly.Module(readFileSync(wasm));
+const instance = new WebAssembly.Instance(module, wasi.getImportObject());
+process.exitCode = wasi.start(instance);
+"#;
+
+impl Runtime for Node {
+    fn name(&self) -> &'static str {
+        "node"
+    }
+
+    fn install_hint(&self) -> String {
+        "you can download Node.js from https://nodejs.org\n".to_string()
+    }
+
+    fn configure(&self, cmd: &mut Command, run: &Run<'_>) {
+        let object = |pairs: &[(String, String)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone().into()))
+                .collect::<serde_json::Map<_, _>>()
+        };
+        let config = serde_json::json!({
+            "preopens": object(run.dirs),
+            "env": object(run.env),
+        });
+        cmd.arg("--experimental-wasi-unstable-preview1")
+            .arg("--no-warnings")
+            .arg("-e")
+            .arg(NODE_SCRIPT)
+            .arg(config.to_string())
+            .arg(run.wasm)
+            .args(run.args);
+    }
+}
+
+/// A runner we know nothing about, which is invoked with the same convention
+/// as `wasmtime`, namely `runner -- wasm args`.
+struct Custom;
+
+impl Runtime for Custom {
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    fn install_hint(&self) -> String {
+        String::new()
+    }
+
+    fn is_known(&self) -> bool {
+        false
+    }
+
+    fn configure(&self, cmd: &mut Command, run: &Run<'_>) {
+        cmd.arg("--").arg(run.wasm).args(run.args);
+    }
+}
+
+const RUNTIMES: &[&(dyn Runtime + Sync)] = &[&Wasmtime, &Wasmer, &WasmEdge, &Wazero, &Node];
+
+/// Looks up a built-in runtime by the name used in `Cargo.toml`.
+pub fn from_name(name: &str) -> Result<&'static (dyn Runtime + Sync)> {
+    match RUNTIMES.iter().find(|r| r.name() == name) {
+        Some(runtime) => Ok(*runtime),
+        None => bail!(
+            "unknown runtime `{}`, `cargo wasi` supports: {}",
+            name,
+            RUNTIMES
+                .iter()
+                .map(|r| r.name())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    }
+}
+
+/// Infers the runtime of a user-specified runner executable from its file
+/// name, falling back to `wasmtime`'s conventions for unknown runners.
+pub fn from_executable(program: &str) -> &'static (dyn Runtime + Sync) {
+    let stem = Path::new(program)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("");
+    match RUNTIMES.iter().find(|r| r.name() == stem) {
+        Some(runtime) => *runtime,
+        None => &Custom,
+    }
+}
+
+/// The runner that wasm files are executed with.
+pub enum Runner {
+    /// A runtime executable, which is either configured through
+    /// `CARGO_TARGET_<TRIPLE>_RUNNER` or is one of the built-in runtimes.
+    External(ExternalRunner),
+    /// Wasmtime linked into `cargo wasi` itself.
+    #[cfg(feature = "embedded-runtime")]
+    Embedded,
+}
+
+/// A `Runner` in a form that can be passed to another process, namely our
+/// runner shim when it executes wasm files itself for `nextest`.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunnerSpec {
+    External {
+        program: String,
+        extra_args: Vec<String>,
+        runtime: String,
+    },
+    Embedded,
+}
+
+pub struct ExternalRunner {
+    program: String,
+    extra_args: Vec<String>,
+    runtime: &'static (dyn Runtime + Sync),
+    // The env var the runner was read from, if it was overridden.
+    var: Option<String>,
+}
+
+impl Runner {
+    /// Selects the runner for `target`, preferring a runner configured in the
+    /// environment over the runtime named in `Cargo.toml`.
+    ///
+    /// By default this is `wasmtime`, which is embedded if `cargo wasi` was
+    /// built with the `embedded-runtime` feature and can run the target.
+    pub fn select(target: Target, runtime: Option<&str>) -> Result<Runner> {
+        let (var, runner) = match target.runner_override() {
+            Some(pair) => pair,
+            None => {
+                let embedded = match runtime {
+                    Some(name) => name == "embedded",
+                    None => cfg!(feature = "embedded-runtime") && embeds(target),
+                };
+                if embedded {
+                    return Runner::embedded(target);
+                }
+                let runtime = from_name(runtime.unwrap_or("wasmtime"))?;
+                return Ok(Runner::External(ExternalRunner {
+                    program: runtime.name().to_string(),
+                    extra_args: Vec::new(),
+                    runtime,
+                    var: None,
+                }));
+            }
+        };
+
+        // Treat the runner variable as an exectable, followed by a whitespace-
+        // separated list of arguments to the executable. This allows the user
+        // to provide arguments which are passed to the runtime without having
+        // to add more command-line argument parsing to this crate.
+        let mut words = runner.split_whitespace();
+        let program = words
+            .next()
+            .ok_or_else(|| anyhow!("${} must not be empty", var))?
+            .to_string();
+        Ok(Runner::External(ExternalRunner {
+            runtime: from_executable(&program),
+            program,
+            extra_args: words.map(|s| s.to_string()).collect(),
+            var: Some(var),
+        }))
+    }
+
+    /// Recreates the runner described by `spec`.
+    pub fn from_spec(spec: RunnerSpec) -> Result<Runner> {
+        match spec {
+            RunnerSpec::External {
+                program,
+                extra_args,
+                runtime,
+            } => Ok(Runner::External(ExternalRunner {
+                runtime: match runtime.as_str() {
+                    "custom" => &Custom,
+                    name => from_name(name)?,
+                },
+                program,
+                extra_args,
+                var: None,
+            })),
+            #[cfg(feature = "embedded-runtime")]
+            RunnerSpec::Embedded => Ok(Runner::Embedded),
+            #[cfg(not(feature = "embedded-runtime"))]
+            RunnerSpec::Embedded => bail!("the embedded runtime isn't available"),
+        }
+    }
+
+    /// Describes this runner for `from_spec` in another process.
+    pub fn spec(&self) -> RunnerSpec {
+        match self {
+            Runner::External(runner) => RunnerSpec::External {
+                program: runner.program.clone(),
+                extra_args: runner.extra_args.clone(),
+                runtime: runner.runtime.name().to_string(),
+            },
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => RunnerSpec::Embedded,
+        }
+    }
+
+    #[cfg(feature = "embedded-runtime")]
+    fn embedded(target: Target) -> Result<Runner> {
+        if !embeds(target) {
+            bail!("the embedded runtime can't run `{}` binaries", target);
+        }
+        Ok(Runner::Embedded)
+    }
+
+    #[cfg(not(feature = "embedded-runtime"))]
+    fn embedded(_target: Target) -> Result<Runner> {
+        bail!(
+            "the embedded runtime requires `cargo wasi` to be built \
+             with the `embedded-runtime` feature"
+        )
+    }
+
+    /// A description of the runner for messages.
+    pub fn name(&self) -> &str {
+        match self {
+            Runner::External(runner) => &runner.program,
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => "embedded wasmtime",
+        }
+    }
+
+    /// Whether the runner is able to preopen directories and set env vars.
+    pub fn passes_config(&self) -> bool {
+        match self {
+            Runner::External(runner) => runner.runtime.is_known(),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => true,
+        }
+    }
+
+    /// Whether the runner is able to enforce memory and fuel limits.
+    pub fn supports_limits(&self) -> bool {
+        match self {
+            Runner::External(runner) => runner.runtime.supports_limits(),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => true,
+        }
+    }
+
+    /// Whether the runner is able to profile the guest.
+    pub fn supports_profiling(&self) -> bool {
+        match self {
+            Runner::External(runner) => runner.runtime.supports_profiling(),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => true,
+        }
+    }
+
+    /// Switches to a runner that native debuggers can step through, which is
+    /// a separate `wasmtime` process rather than the embedded runtime.
+    pub fn debuggable(self) -> Result<Runner> {
+        match self {
+            Runner::External(runner) => {
+                if runner.runtime.debug_args().is_none() {
+                    bail!(
+                        "`{}` doesn't register its code with native debuggers, \
+                         `cargo wasi debug` requires `wasmtime`",
+                        runner.program,
+                    );
+                }
+                Ok(Runner::External(runner))
+            }
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => Ok(Runner::External(ExternalRunner::wasmtime())),
+        }
+    }
+
+    /// Switches to a runner in a process of its own, which is the `wasmtime`
+    /// executable in place of the embedded runtime.
+    pub fn external(self) -> Runner {
+        match self {
+            Runner::External(runner) => Runner::External(runner),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => Runner::External(ExternalRunner::wasmtime()),
+        }
+    }
+
+    /// The command which performs `run` in a process of its own, for the
+    /// wrappers that `install` writes.
+    pub fn command(&self, run: &Run<'_>) -> Command {
+        match self {
+            Runner::External(runner) => runner.command(run),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => ExternalRunner::wasmtime().command(run),
+        }
+    }
+
+    /// The command which performs `run` with the runtime's debug info for
+    /// native debuggers enabled, if it has any.
+    pub fn debug_command(&self, run: &Run<'_>) -> Option<Command> {
+        match self {
+            Runner::External(runner) => {
+                let mut cmd = Command::new(&runner.program);
+                cmd.args(&runner.extra_args);
+                cmd.args(runner.runtime.debug_args()?);
+                runner.runtime.configure(&mut cmd, run);
+                Some(cmd)
+            }
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => None,
+        }
+    }
+
+    /// Checks that the runner exists before we build anything, with
+    /// instructions on how to fix it if not.
+    pub fn check_installed(&self) -> Result<()> {
+        match self {
+            Runner::External(runner) => runner.check_installed(),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => Ok(()),
+        }
+    }
+
+    /// Performs `run` with this runner.
+    pub fn run(&self, run: &Run<'_>) -> Result<()> {
+        match self {
+            Runner::External(runner) => runner.run(run, None),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => crate::embedded::run(run),
+        }
+    }
+
+    /// Performs `run` like `run` does, but also keeps a copy of what it
+    /// prints, along with its trap, in `captured`.
+    ///
+    /// The embedded runtime only captures whole outputs, so its output is
+    /// printed once the run finishes rather than as it goes.
+    pub fn run_captured(&self, run: &Run<'_>, captured: &mut Output) -> Result<()> {
+        match self {
+            Runner::External(runner) => runner.run(run, Some(captured)),
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => {
+                *captured = self.output(run)?;
+                let mut out = io::stdout().lock();
+                drop(out.write_all(&captured.stdout));
+                drop(out.flush());
+                drop(io::stderr().write_all(&captured.stderr));
+                captured.check(run)
+            }
+        }
+    }
+
+    /// Performs `run` like `run` does, but appends its output to `buffer`
+    /// instead of printing it.
+    pub fn run_buffered(&self, run: &Run<'_>, buffer: &mut Buffer) -> Result<()> {
+        let output = self.output(run)?;
+        buffer.stdout.extend_from_slice(&output.stdout);
+        buffer.stderr.extend_from_slice(&output.stderr);
+        output.check(run)
+    }
+
+    /// Performs `run` with this runner, capturing its output instead of
+    /// printing it.
+    ///
+    /// Its stderr always goes through `backtrace::filter`, as the trap is
+    /// reported along with the output.
+    pub fn output(&self, run: &Run<'_>) -> Result<Output> {
+        match self {
+            Runner::External(runner) => {
+                let mut cmd = runner.command(run);
+                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+                let mut child = cmd
+                    .spawn()
+                    .with_context(|| format!("failed to create process {:?}", cmd))?;
+                let mut stdout = child.stdout.take().unwrap();
+                let stdout = thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    stdout.read_to_end(&mut buf).map(|_| buf)
+                });
+                let stderr = child.stderr.take().unwrap();
+                let wasm = PathBuf::from(run.wasm);
+                let stderr = thread::spawn(move || {
+                    let mut buf = Vec::new();
+                    backtrace::filter(stderr, &mut buf, &wasm).map(|trap| (buf, trap))
+                });
+                let status = wait(&mut child, run.limits.timeout)?;
+                let stdout = stdout.join().unwrap()?;
+                let (mut stderr, trap) = stderr.join().unwrap()?;
+                let mut output = Output {
+                    success: status.is_some_and(|s| s.success()),
+                    code: status.and_then(|s| s.code()),
+                    stdout,
+                    stderr: Vec::new(),
+                    trap,
+                };
+                if let Some(limit) = run
+                    .limits
+                    .exceeded(status.is_none(), output.trap.as_deref())
+                {
+                    let error = run.exceeded(limit).to_string();
+                    stderr.extend_from_slice(format!("error: {}\n", error).as_bytes());
+                    output.trap = Some(error);
+                }
+                output.stderr = stderr;
+                Ok(output)
+            }
+            #[cfg(feature = "embedded-runtime")]
+            Runner::Embedded => crate::embedded::output(run),
+        }
+    }
+}
+
+impl Output {
+    /// Turns the output of `run` into an error if it failed.
+    ///
+    /// Like `hide_normal_process_exit`, only abnormal exits get an error
+    /// message, as the output describes any trap or exceeded limit.
+    pub fn check(&self, run: &Run<'_>) -> Result<()> {
+        if self.success {
+            return Ok(());
+        }
+        match self.code {
+            Some(code) if (0..128).contains(&code) => Err(GuestExit(code).into()),
+            _ => bail!("failed to run `{}`", run.wasm),
+        }
+    }
+}
+
+impl Run<'_> {
+    pub fn exceeded(&self, limit: Limit) -> LimitExceeded {
+        LimitExceeded {
+            wasm: self.wasm.to_string(),
+            limit,
+        }
+    }
+}
+
+/// Waits for `child` to exit, killing it once `timeout` has passed, in which
+/// case `None` is returned.
+fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(Some(child.wait()?)),
+    };
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            // It may have exited in the meantime, which is fine.
+            drop(child.kill());
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+impl ExternalRunner {
+    /// The `wasmtime` executable in $PATH, which stands in for the embedded
+    /// runtime where a separate process is needed.
+    #[cfg(feature = "embedded-runtime")]
+    fn wasmtime() -> ExternalRunner {
+        ExternalRunner {
+            program: Wasmtime.name().to_string(),
+            extra_args: Vec::new(),
+            runtime: &Wasmtime,
+            var: None,
+        }
+    }
+
+    fn check_installed(&self) -> Result<()> {
+        if let Some(var) = &self.var {
+            // check if the override is either a valid path or command found on $PATH
+            if !(Path::new(&self.program).exists() || which::which(&self.program).is_ok()) {
+                bail!(
+                    "failed to find `{}` (specified by ${}) \
+                     on the filesytem or in $PATH, you'll want to fix the path or unset \
+                     the ${} environment variable before \
+                     running this command\n",
+                    self.program,
+                    var,
+                    var,
+                );
+            }
+        } else if which::which(&self.program).is_err() {
+            bail!(
+                "failed to find `{}` in $PATH, you'll want to \
+                 install `{}` before running this command\n{}",
+                self.program,
+                self.program,
+                self.runtime.install_hint(),
+            );
+        }
+        Ok(())
+    }
+
+    fn command(&self, run: &Run<'_>) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(&self.extra_args);
+        self.runtime.configure(&mut cmd, run);
+        cmd
+    }
+
+    /// Performs `run`, with its output copied into `capture` as it's printed
+    /// if requested.
+    ///
+    /// Its stderr only goes through `backtrace::filter` if it's captured, if
+    /// backtraces were requested, or if the trap is needed to tell whether a
+    /// limit stopped it, and is otherwise left alone.
+    fn run(&self, run: &Run<'_>, capture: Option<&mut Output>) -> Result<()> {
+        let mut cmd = self.command(run);
+        let captures = capture.is_some();
+        if captures || backtrace::requested() || run.limits.trap_when_exceeded() {
+            cmd.stderr(Stdio::piped());
+        }
+        if captures {
+            cmd.stdout(Stdio::piped());
+        }
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("failed to create process {:?}", cmd))?;
+        let stdout = child.stdout.take().map(|mut stdout| {
+            thread::spawn(move || -> io::Result<Vec<u8>> {
+                let mut captured = Vec::new();
+                let mut buf = [0; 8192];
+                loop {
+                    let n = stdout.read(&mut buf)?;
+                    if n == 0 {
+                        return Ok(captured);
+                    }
+                    let mut out = io::stdout().lock();
+                    out.write_all(&buf[..n])?;
+                    out.flush()?;
+                    captured.extend_from_slice(&buf[..n]);
+                }
+            })
+        });
+        let stderr = child.stderr.take().map(|stderr| {
+            let wasm = PathBuf::from(run.wasm);
+            thread::spawn(move || -> io::Result<(Vec<u8>, Option<String>)> {
+                let mut out = TeeStderr {
+                    copy: captures.then(Vec::new),
+                };
+                let trap = backtrace::filter(stderr, &mut out, &wasm)?;
+                Ok((out.copy.unwrap_or_default(), trap))
+            })
+        });
+        let status = wait(&mut child, run.limits.timeout)?;
+        let (stderr, trap) = match stderr {
+            Some(stderr) => stderr.join().unwrap()?,
+            None => (Vec::new(), None),
+        };
+        let exceeded = run.limits.exceeded(status.is_none(), trap.as_deref());
+        if let (Some(capture), Some(stdout)) = (capture, stdout) {
+            *capture = Output {
+                success: status.is_some_and(|s| s.success()),
+                code: status.and_then(|s| s.code()),
+                stdout: stdout.join().unwrap()?,
+                stderr,
+                trap: match exceeded {
+                    Some(limit) => Some(run.exceeded(limit).to_string()),
+                    None => trap,
+                },
+            };
+        }
+        if let Some(limit) = exceeded {
+            return Err(run.exceeded(limit).into());
+        }
+        utils::check_success(&cmd, &status.unwrap(), &[], &[])
+    }
+}
+
+/// Prints to stderr, keeping a copy of what's printed if `copy` is set.
+struct TeeStderr {
+    copy: Option<Vec<u8>>,
+}
+
+impl Write for TeeStderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = io::stderr().write(buf)?;
+        if let Some(copy) = &mut self.copy {
+            copy.extend_from_slice(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Whether the embedded runtime is able to run binaries for `target`, which
+/// excludes components and modules using threads.
+fn embeds(target: Target) -> bool {
+    matches!(target, Target::Wasi | Target::Wasip1)
+}
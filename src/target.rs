@@ -0,0 +1,102 @@
+use anyhow::{bail, Result};
+use std::env;
+use std::fmt;
+
+/// The WASI flavor of WebAssembly target that `cargo wasi` is compiling for.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The original `wasm32-wasi` target, which newer toolchains have renamed
+    /// to `wasm32-wasip1`.
+    Wasi,
+    #[default]
+    Wasip1,
+    Wasip1Threads,
+    Wasip2,
+}
+
+impl Target {
+    const ALL: &'static [Target] = &[
+        Target::Wasip1,
+        Target::Wasip1Threads,
+        Target::Wasip2,
+        Target::Wasi,
+    ];
+
+    /// Selects the target to compile for, in order of precedence:
+    ///
+    /// * A `--target` flag passed through to Cargo on the command line.
+    /// * The `CARGO_WASI_TARGET` env var.
+    /// * The `wasi-target` key in `[package.metadata]`.
+    /// * Otherwise `wasm32-wasip1`.
+    pub fn select(cli: Option<&str>, manifest: Option<&str>) -> Result<Target> {
+        if let Some(triple) = cli {
+            return Target::from_triple(triple);
+        }
+        if let Ok(triple) = env::var("CARGO_WASI_TARGET") {
+            return Target::from_triple(&triple);
+        }
+        match manifest {
+            Some(triple) => Target::from_triple(triple),
+            None => Ok(Target::default()),
+        }
+    }
+
+    pub fn from_triple(triple: &str) -> Result<Target> {
+        match Target::ALL.iter().find(|t| t.triple() == triple) {
+            Some(target) => Ok(*target),
+            None => bail!(
+                "unsupported target `{}`, `cargo wasi` supports: {}",
+                triple,
+                Target::ALL
+                    .iter()
+                    .map(|t| t.triple())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        }
+    }
+
+    pub fn triple(&self) -> &'static str {
+        match self {
+            Target::Wasi => "wasm32-wasi",
+            Target::Wasip1 => "wasm32-wasip1",
+            Target::Wasip1Threads => "wasm32-wasip1-threads",
+            Target::Wasip2 => "wasm32-wasip2",
+        }
+    }
+
+    /// The `CARGO_TARGET_<TRIPLE>_RUNNER` env var Cargo consults for this
+    /// target.
+    pub fn runner_env_var(&self) -> String {
+        format!(
+            "CARGO_TARGET_{}_RUNNER",
+            self.triple().to_uppercase().replace('-', "_")
+        )
+    }
+
+    /// Returns the user-configured runner for this target, if any, along with
+    /// the name of the env var it was read from.
+    ///
+    /// For `wasm32-wasip1` the historical `CARGO_TARGET_WASM32_WASI_RUNNER` is
+    /// also consulted so existing setups keep working after the rename.
+    pub fn runner_override(&self) -> Option<(String, String)> {
+        let mut vars = vec![self.runner_env_var()];
+        if *self == Target::Wasip1 {
+            vars.push(Target::Wasi.runner_env_var());
+        }
+        vars.into_iter()
+            .find_map(|var| env::var(&var).ok().map(|runner| (var, runner)))
+    }
+
+    /// Whether rustc links this target's binaries into components rather than
+    /// core wasm modules.
+    pub fn emits_component(&self) -> bool {
+        *self == Target::Wasip2
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.triple())
+    }
+}
@@ -110,6 +110,9 @@ pub fn hide_normal_process_exit(error: Error, config: &Config) -> Error {
 
 /// Checks if `Error` has been hidden via `hide_normal_process_exit` above.
 pub fn normal_process_exit_code(error: &Error) -> Option<i32> {
+    if let Some(exit) = error.downcast_ref::<GuestExit>() {
+        return Some(exit.0);
+    }
     let process_error = error.downcast_ref::<ProcessError>()?;
     if !process_error.hidden {
         return None;
@@ -146,6 +149,48 @@ impl fmt::Display for ProcessError {
 
 impl std::error::Error for ProcessError {}
 
+/// The exit code of a wasm program that was run in-process and exited
+/// unsuccessfully, which is passed through as our own exit code.
+#[derive(Debug)]
+pub struct GuestExit(pub i32);
+
+impl fmt::Display for GuestExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "wasm program exited with status {}", self.0)
+    }
+}
+
+impl std::error::Error for GuestExit {}
+
+/// Reads an unsigned LEB128-encoded integer, as used throughout the wasm
+/// binary format, from the front of `data`.
+pub fn read_uleb128(data: &mut &[u8]) -> Option<u32> {
+    let mut result = 0u32;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        result |= u32::from(byte & 0x7f).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+        shift += 7;
+    }
+}
+
+/// Appends `value` to `dst` as an unsigned LEB128-encoded integer.
+pub fn write_uleb128(dst: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            dst.push(byte);
+            return;
+        }
+        dst.push(byte | 0x80);
+    }
+}
+
 /// Finds an HTTP proxy, in order:
 /// * `http_proxy` env var
 /// * `HTTP_PROXY` env var
@@ -94,6 +94,28 @@ fn fix_works() {
     p.cargo_wasi("fix --allow-no-vcs").assert().success();
 }
 
+#[test]
+fn passthrough_subcommands() -> Result<()> {
+    let p = support::project()
+        .file("src/lib.rs", "/// Docs\npub fn foo() {}")
+        .build();
+
+    p.cargo_wasi("tree")
+        .assert()
+        .stdout(predicate::str::starts_with("foo v1.0.0"))
+        .success();
+    p.cargo_wasi("rustc -- --print cfg")
+        .assert()
+        .stdout(predicate::str::contains("target_os=\"wasi\""))
+        .success();
+    p.cargo_wasi("doc").assert().success();
+    assert!(p
+        .root()
+        .join("target/wasm32-wasip1/doc/foo/index.html")
+        .exists());
+    Ok(())
+}
+
 #[test]
 fn rust_names_demangled() -> Result<()> {
     let p = support::project()
@@ -182,8 +204,8 @@ $",
 .*Running \"cargo\" .*
 .*Compiling foo v1.0.0 .*
 .*Running `rustc.*`
-.*Finished dev .*
 .*Processing .*foo.rustc.wasm
+.*Finished dev .*
 $",
         )?)
         .success();
@@ -235,8 +257,8 @@ fn check_output_release() -> Result<()> {
         .stderr(is_match(
             "^\
 .*Compiling foo v1.0.0 .*
+.*Optimizing foo.wasm with wasm-opt
 .*Finished release .*
-.*Optimizing with wasm-opt
 $",
         )?)
         .success();
@@ -254,10 +276,10 @@ $",
 .*Running \"cargo\" .*
 .*Compiling foo v1.0.0 .*
 .*Running `rustc.*`
-.*Finished release .*
 .*Processing .*foo.rustc.wasm
-.*Optimizing with wasm-opt
+.*Optimizing foo.wasm with wasm-opt
 .*Running \".*wasm-opt.*
+.*Finished release .*
 $",
         )?)
         .success();
@@ -451,7 +473,7 @@ $",
         .assert()
         .stdout("")
         // error should include this environment variable
-        .stderr(is_match("CARGO_TARGET_WASM32_WASI_RUNNER")?)
+        .stderr(is_match("CARGO_TARGET_WASM32_WASIP1_RUNNER")?)
         .failure();
 
     // override with a working runtime works
@@ -517,7 +539,7 @@ $",
         .build()
         .cargo_wasi("run")
         .assert()
-        .stdout(is_match("target.wasm32-wasi.debug.foo.wasm")?)
+        .stdout(is_match("target.wasm32-wasip1.debug.foo.wasm")?)
         .stderr(is_match(
             "^\
 .*Compiling foo v1.0.0 .*
@@ -861,3 +883,1501 @@ fn verbose_build_script_works() -> Result<()> {
     p.cargo_wasi("build -vv").assert().success();
     Ok(())
 }
+
+#[test]
+fn target_from_manifest() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasi-target = "wasm32-wasip1-threads"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasi("build").assert().success();
+    assert!(p
+        .target_wasm("wasm32-wasip1-threads", "debug", "foo")
+        .exists());
+    assert!(!p.debug_wasm("foo").exists());
+    Ok(())
+}
+
+#[test]
+fn target_from_cli_and_env() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // an explicit `--target` takes precedence over the env var
+    p.cargo_wasi("build --target wasm32-wasip2")
+        .env("CARGO_WASI_TARGET", "wasm32-wasip1-threads")
+        .assert()
+        .success();
+    let bytes = std::fs::read(p.target_wasm("wasm32-wasip2", "debug", "foo"))?;
+    assert_eq!(
+        &bytes[4..8],
+        &[0x0d, 0x00, 0x01, 0x00],
+        "expected a component"
+    );
+
+    p.cargo_wasi("build")
+        .env("CARGO_WASI_TARGET", "wasm32-wasip1-threads")
+        .assert()
+        .success();
+    assert!(p
+        .target_wasm("wasm32-wasip1-threads", "debug", "foo")
+        .exists());
+    Ok(())
+}
+
+#[test]
+fn target_unsupported() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasi("build --target x86_64-unknown-linux-gnu")
+        .assert()
+        .stderr(is_match(
+            "^error: unsupported target `x86_64-unknown-linux-gnu`, `cargo wasi` supports: .*\n$",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn message_format_json() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    let output = p
+        .cargo_wasi("build --message-format json")
+        .output()
+        .context("failed to run cargo-wasi")?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?;
+    let reasons = stdout
+        .lines()
+        .map(|line| {
+            let msg = serde_json::from_str::<serde_json::Value>(line)?;
+            Ok(msg["reason"].as_str().unwrap_or("").to_string())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    assert!(reasons.iter().any(|r| r == "compiler-artifact"));
+    assert!(reasons.iter().any(|r| r == "wasi-artifact-processed"));
+    assert!(reasons.iter().any(|r| r == "build-finished"));
+    Ok(())
+}
+
+#[test]
+fn message_format_short() -> Result<()> {
+    support::project()
+        .file("src/main.rs", "fn main() { let x = 1; }")
+        .build()
+        .cargo_wasi("build --message-format=short")
+        .assert()
+        .stdout("")
+        .stderr(is_match("src.main.rs:1:17: warning: unused variable: `x`")?)
+        .success();
+    Ok(())
+}
+
+#[test]
+fn many_artifacts_in_parallel() -> Result<()> {
+    let p = support::project()
+        .file("src/bin/a.rs", "fn main() {}")
+        .file("src/bin/b.rs", "fn main() {}")
+        .file("src/bin/c.rs", "fn main() {}")
+        .file("src/bin/d.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasi("build -j2").assert().success();
+    for name in ["a", "b", "c", "d"].iter() {
+        let bytes = std::fs::read(p.debug_wasm(name)).context("failed to read wasm")?;
+        assert_demangled(&bytes)?;
+        assert!(p
+            .build_dir()
+            .join("wasm32-wasip1/debug")
+            .join(format!("{}.rustc.wasm", name))
+            .exists());
+    }
+
+    p.cargo_wasi("build --jobs=-1").assert().success();
+    p.cargo_wasi("build --jobs 0")
+        .assert()
+        .stderr("error: invalid value for `--jobs`: `0`\n")
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn wasm_cache() -> Result<()> {
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasi("build -v")
+        .assert()
+        .stderr(is_match("Processing .*foo.rustc.wasm")?)
+        .success();
+
+    // `cargo clean` doesn't throw away our post-processed output
+    Command::new("cargo")
+        .arg("clean")
+        .current_dir(p.root())
+        .assert()
+        .success();
+    p.cargo_wasi("build -v")
+        .assert()
+        .stderr(is_match("Processing")?.not())
+        .success();
+    assert_demangled(&std::fs::read(p.debug_wasm("foo"))?)?;
+
+    // ... but changing our configuration does invalidate it, even though
+    // Cargo considers the build fresh
+    std::fs::write(
+        p.root().join("Cargo.toml"),
+        r#"
+            [package]
+            name = "foo"
+            version = "1.0.0"
+
+            [package.metadata]
+            wasm-producers-section = false
+        "#,
+    )?;
+    p.cargo_wasi("build -v")
+        .assert()
+        .stderr(is_match("Fresh foo")?.and(is_match("Processing .*foo.rustc.wasm")?))
+        .success();
+
+    p.cargo_wasi("self clean --wasm-cache")
+        .assert()
+        .stderr(is_match("Removing .*wasm-cache")?)
+        .success();
+    assert!(!p.root().join("wasm-cache").exists());
+    p.cargo_wasi("self clean --bad")
+        .assert()
+        .stderr("error: unsupported `self clean` option: --bad\n")
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn wasm_opt_flags() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-opt-flags = ["--converge"]
+
+                [package.metadata.wasm-opt.release]
+                flags = ["-Oz", "--low-memory-unused"]
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // use a missing `wasm-opt` so the error shows how it was invoked
+    p.cargo_wasi("build --release")
+        .env("WASM_OPT", "my-wasm-opt")
+        .assert()
+        .stderr(is_match(
+            "failed to create process \"my-wasm-opt\" \"[^\"]*input.wasm\" \"-o\" .* \
+             \"--converge\" \"-Oz\" \"--low-memory-unused\"",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn wasm_opt_flags_test_and_bench_profiles() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [profile.test]
+                opt-level = 1
+                debug = false
+
+                [package.metadata.wasm-opt.dev]
+                flags = ["--dev-flag"]
+
+                [package.metadata.wasm-opt.test]
+                flags = ["--test-flag"]
+
+                [package.metadata.wasm-opt.release]
+                flags = ["--release-flag"]
+
+                [package.metadata.wasm-opt.bench]
+                flags = ["--bench-flag"]
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    // `test` artifacts live in `debug` and `bench` ones in `release`, but
+    // they're built with profiles of their own.
+    p.cargo_wasi("test")
+        .env("WASM_OPT", "my-wasm-opt")
+        .assert()
+        .stderr(is_match(
+            "failed to create process \"my-wasm-opt\" .*\"--test-flag\"",
+        )?)
+        .stderr(predicate::str::contains("--dev-flag").not())
+        .code(1);
+    p.cargo_wasi("bench")
+        .env("WASM_OPT", "my-wasm-opt")
+        .assert()
+        .stderr(is_match(
+            "failed to create process \"my-wasm-opt\" .*\"--bench-flag\"",
+        )?)
+        .stderr(predicate::str::contains("--release-flag").not())
+        .code(1);
+    p.cargo_wasi("test --release")
+        .env("WASM_OPT", "my-wasm-opt")
+        .assert()
+        .stderr(is_match(
+            "failed to create process \"my-wasm-opt\" .*\"--release-flag\"",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn wasm_opt_features() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/main.rs",
+            r#"
+                use std::arch::wasm32::*;
+
+                fn main() {
+                    let v = i32x4_splat(std::env::args().count() as i32);
+                    println!("{}", i32x4_extract_lane::<0>(i32x4_add(v, v)));
+                }
+            "#,
+        )
+        .build();
+
+    // the module's features are passed along so `wasm-opt` can validate it
+    p.cargo_wasi("build --release")
+        .env("WASM_OPT", "my-wasm-opt")
+        .env("RUSTFLAGS", "-Ctarget-feature=+simd128")
+        .assert()
+        .stderr(is_match(
+            "failed to create process \"my-wasm-opt\" .* \
+             \"--enable-bulk-memory\" .*\"--enable-simd\"",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn split_debuginfo() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-split-debuginfo = true
+
+                [profile.release]
+                debug = 1
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasi("build").assert().success();
+    let bytes = std::fs::read(p.debug_wasm("foo")).context("failed to read wasm")?;
+    let sections = custom_sections(&bytes)?;
+    assert!(!sections.iter().any(|s| s.starts_with(".debug_")));
+    assert!(sections.contains(&"name"));
+    assert!(sections.contains(&"external_debug_info"));
+    let debug = p.debug_wasm("foo").with_extension("debug.wasm");
+    let bytes = std::fs::read(&debug).context("failed to read debug wasm")?;
+    assert!(custom_sections(&bytes)?.contains(&".debug_info"));
+
+    // `wasm-opt` now runs despite the debuginfo, keeping it up to date
+    p.cargo_wasi("build --release")
+        .env("WASM_OPT", "my-wasm-opt")
+        .assert()
+        .stderr(is_match(
+            "failed to create process \"my-wasm-opt\" .* \"--debuginfo\"",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn component() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                component = true
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build();
+
+    p.cargo_wasi("build")
+        .assert()
+        .stderr(predicate::str::contains(
+            "Componentizing foo.component.wasm with the WASI adapter",
+        ))
+        .success();
+    let bytes = std::fs::read(p.debug_wasm("foo"))?;
+    assert_eq!(&bytes[4..8], &[0x01, 0x00, 0x00, 0x00], "expected a module");
+    let bytes = std::fs::read(p.debug_wasm("foo").with_extension("component.wasm"))?;
+    assert_eq!(
+        &bytes[4..8],
+        &[0x0d, 0x00, 0x01, 0x00],
+        "expected a component"
+    );
+    Ok(())
+}
+
+#[test]
+fn wizer() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wizer]
+                init-func = "init"
+                allow-wasi = true
+            "#,
+        )
+        .file(
+            "src/main.rs",
+            r#"
+                #[export_name = "init"]
+                pub extern "C" fn init() {}
+
+                fn main() {}
+            "#,
+        )
+        .build();
+
+    // use a missing `wizer` so the error shows how it was invoked
+    p.cargo_wasi("build")
+        .env("WIZER", "my-wizer")
+        .assert()
+        .stderr(is_match(
+            "Initializing foo.wasm with wizer\n\
+             (.|\n)*failed to create process \"my-wizer\" \"[^\"]*input.wasm\" \
+             \"-o\" \"[^\"]*output.wasm\" \"--init-func\" \"init\" \"--allow-wasi\"",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn wizer_only_initializes_binaries_with_init_func() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wizer]
+                init-func = "init"
+            "#,
+        )
+        .file(
+            "src/main.rs",
+            r#"
+                #[export_name = "init"]
+                pub extern "C" fn init() {}
+
+                fn main() {}
+
+                #[test]
+                fn works() {}
+            "#,
+        )
+        .file("src/bin/other.rs", "fn main() {}")
+        .file(
+            "bin/wizer",
+            "#!/bin/sh
+cp \"$1\" \"$3\"\n",
+        )
+        .build();
+    let wizer = p.root().join("bin/wizer");
+    std::fs::set_permissions(&wizer, std::fs::Permissions::from_mode(0o755))?;
+
+    // neither the test harness nor `other` export `init`
+    p.cargo_wasi("build --all-targets")
+        .env("WIZER", &wizer)
+        .assert()
+        .stderr(predicate::str::contains("Initializing foo.wasm with wizer"))
+        .stderr(predicate::str::contains("with wizer").count(1))
+        .success();
+    Ok(())
+}
+
+#[test]
+fn runtime_from_manifest() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                runtime = "node"
+            "#,
+        )
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    let args = std::env::args().skip(1).collect::<Vec<_>>();
+                    println!("hello {:?}", args);
+                }
+            "#,
+        )
+        .build();
+    if which::which("node").is_err() {
+        return Ok(());
+    }
+
+    p.cargo_wasi("run -- a --b")
+        .assert()
+        .stdout("hello [\"a\", \"--b\"]\n")
+        .success();
+    Ok(())
+}
+
+#[test]
+fn runtime_unknown() -> Result<()> {
+    support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                runtime = "not-a-runtime"
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .build()
+        .cargo_wasi("run")
+        .assert()
+        .stderr(is_match(
+            "unknown runtime `not-a-runtime`, `cargo wasi` supports: \
+             wasmtime, wasmer, wasmedge, wazero, node",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn runner_dirs_and_env() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                runtime = "node"
+                dirs = ["fixtures"]
+                mapdir = { "/data" = "fixtures" }
+                env = { FOO = "bar" }
+                inherit-env = ["INHERITED", "NOT_SET"]
+            "#,
+        )
+        .file("fixtures/a.txt", "hello")
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    println!("{}", std::fs::read_to_string("fixtures/a.txt").unwrap());
+                    println!("{}", std::fs::read_to_string("/data/a.txt").unwrap());
+                    let mut env = std::env::vars().collect::<Vec<_>>();
+                    env.sort();
+                    println!("{:?}", env);
+                }
+            "#,
+        )
+        .build();
+    if which::which("node").is_err() {
+        return Ok(());
+    }
+
+    p.cargo_wasi("run")
+        .env("INHERITED", "1")
+        .env("NOT_INHERITED", "1")
+        .assert()
+        .stdout(
+            "hello\n\
+             hello\n\
+             [(\"FOO\", \"bar\"), (\"INHERITED\", \"1\")]\n",
+        )
+        .success();
+    Ok(())
+}
+
+#[test]
+fn symbolicated_backtrace() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    inner();
+                }
+
+                #[inline(never)]
+                fn inner() {
+                    use std::io::Write;
+                    eprint!("prompt> ");
+                    std::io::stderr().write_all(b"\xff\n").unwrap();
+                    std::process::abort();
+                }
+            "#,
+        )
+        .build();
+
+    // stderr is left alone unless backtraces are requested
+    let output = p
+        .cargo_wasi("run")
+        .env("CARGO_TARGET_WASM32_WASIP1_RUNNER", "wasmtime")
+        .env_remove("RUST_BACKTRACE")
+        .env_remove("WASMTIME_BACKTRACE_DETAILS")
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(is_match(r"!foo::inner::h[0-9a-f]+\n")?.eval(&stderr));
+    assert!(!is_match(r"main\.rs:\d+:\d+")?.eval(&stderr));
+
+    // The embedded runtime prints Wasmtime's own backtraces
+    let output = p
+        .cargo_wasi("run")
+        .env("CARGO_TARGET_WASM32_WASIP1_RUNNER", "wasmtime")
+        .env("RUST_BACKTRACE", "1")
+        .output()?;
+    assert!(!output.status.success());
+    // everything but the frames is copied byte for byte
+    assert!(output.stderr.windows(10).any(|w| w == b"prompt> \xff\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        is_match(r"\n +\d+: +0x[0-9a-f]+ - foo::inner\n +at .*src[/\\]main\.rs:11:21\n",)?
+            .eval(&stderr)
+    );
+    assert!(
+        is_match(r"\n +\d+: +0x[0-9a-f]+ - foo::main\n +at .*src[/\\]main\.rs:3:21\n",)?
+            .eval(&stderr)
+    );
+
+    // Node.js prints backtraces in V8's format instead
+    if which::which("node").is_err() {
+        return Ok(());
+    }
+    let output = p
+        .cargo_wasi("run")
+        .env("CARGO_TARGET_WASM32_WASIP1_RUNNER", "node")
+        .env("RUST_BACKTRACE", "1")
+        .output()?;
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(is_match(r"\n    at foo::inner \(.*src[/\\]main\.rs:11:21\)\n")?.eval(&stderr));
+    Ok(())
+}
+
+#[test]
+fn isolate_tests() -> Result<()> {
+    let p = support::project()
+        .file(
+            "src/lib.rs",
+            r#"
+                #[test]
+                fn a_fails() {
+                    assert_eq!(1, 2);
+                }
+
+                #[test]
+                fn b_passes() {}
+
+                #[test]
+                #[ignore]
+                fn c_ignored() {}
+            "#,
+        )
+        .build();
+
+    p.cargo_wasi("test --lib --isolate-tests")
+        .assert()
+        .stdout(is_match(
+            "(?s)^\n\
+             running 3 tests\n\
+             test a_fails ... FAILED\n\
+             test b_passes ... ok\n\
+             test c_ignored ... ignored\n\
+             \n\
+             failures:\n\
+             \n\
+             ---- a_fails stdout ----\n\
+             .*panicked at src.lib.rs:4:21.*\n\
+             failures:\n    a_fails\n\
+             \n\
+             test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; .*$",
+        )?)
+        .code(101);
+
+    p.cargo_wasi("test --lib --isolate-tests -- b_")
+        .assert()
+        .stdout(is_match(
+            "test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 2 filtered out;",
+        )?)
+        .success();
+    Ok(())
+}
+
+#[test]
+fn isolate_tests_custom_harness() -> Result<()> {
+    // Binaries without libtest ignore `--list`, so they must only run once.
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [[test]]
+                name = "custom"
+                harness = false
+
+                [package.metadata.wasi.runner]
+                dirs = ["."]
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .file(
+            "tests/custom.rs",
+            r#"
+                use std::io::Write;
+
+                fn main() {
+                    let mut runs = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open("runs.txt")
+                        .unwrap();
+                    writeln!(runs, "ran").unwrap();
+                    println!("custom harness ran");
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasi("test --test custom --isolate-tests")
+        .assert()
+        .stdout(predicate::str::contains("custom harness ran").count(1))
+        .success();
+    assert_eq!(std::fs::read_to_string(p.root().join("runs.txt"))?, "ran\n");
+    Ok(())
+}
+
+#[test]
+fn test_jobs() -> Result<()> {
+    let p = support::project()
+        .file("src/lib.rs", "")
+        .file("tests/a.rs", "#[test] fn a_ok() {}")
+        .file("tests/b.rs", "#[test] fn b_fails() { panic!() }")
+        .file("tests/c.rs", "#[test] fn c_ok() {}")
+        .build();
+
+    // Every binary runs despite the failure, with its output in one piece
+    p.cargo_wasi("test --test-jobs 3")
+        .assert()
+        .stdout(is_match(
+            "\nrunning 1 test\ntest a_ok \\.\\.\\. ok\n\ntest result: ok\\.",
+        )?)
+        .stdout(is_match(
+            "\nrunning 1 test\ntest c_ok \\.\\.\\. ok\n\ntest result: ok\\.",
+        )?)
+        .stderr(is_match("error: failed to run `.*b-[0-9a-f]+\\.wasm`\n$")?)
+        .code(1);
+
+    p.cargo_wasi("test")
+        .assert()
+        .stdout(predicate::str::contains("c_ok").not())
+        .failure();
+    p.cargo_wasi("test --no-fail-fast")
+        .assert()
+        .stdout(predicate::str::contains("test c_ok ... ok"))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn nextest() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A stand-in for `cargo nextest` which runs each test in its own process
+    // through the target runner, the way nextest does.
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                env = { FOO = "bar" }
+            "#,
+        )
+        .file(
+            "src/lib.rs",
+            r#"
+                #[test]
+                fn env_passes() {
+                    assert_eq!(std::env::var("FOO").as_deref(), Ok("bar"));
+                }
+
+                #[test]
+                fn fails() {
+                    panic!();
+                }
+            "#,
+        )
+        .file(
+            "bin/cargo-nextest",
+            r#"#!/bin/sh
+echo "args: $*"
+for bin in target/wasm32-wasip1/debug/deps/foo-*.wasm; do
+    case "$bin" in *.rustc.wasm|*.wasi.wasm) continue;; esac
+    for test in $("$CARGO_TARGET_WASM32_WASIP1_RUNNER" "$bin" --list --format terse | sed -n 's/: test$//p'); do
+        if "$CARGO_TARGET_WASM32_WASIP1_RUNNER" "$bin" --exact "$test" >/dev/null 2>&1; then
+            echo "PASS $test"
+        else
+            echo "FAIL $test"
+        fi
+    done
+done
+exit 100
+"#,
+        )
+        .build();
+    let bin = p.root().join("bin");
+    std::fs::set_permissions(
+        bin.join("cargo-nextest"),
+        std::fs::Permissions::from_mode(0o755),
+    )?;
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![bin];
+    paths.extend(std::env::split_paths(&path));
+
+    p.cargo_wasi("nextest run --cargo-profile dev -E all()")
+        .env("PATH", std::env::join_paths(paths)?)
+        .assert()
+        .stdout(is_match(
+            "^args: nextest run --target wasm32-wasip1 --cargo-profile dev -E all\\(\\)\n\
+             PASS env_passes\n\
+             FAIL fails\n$",
+        )?)
+        .code(100);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn coverage() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Stand-ins for the LLVM tools, which need to match the LLVM of rustc.
+    let p = support::project()
+        .file(
+            "src/lib.rs",
+            r#"
+                pub fn double(x: u32) -> u32 {
+                    x * 2
+                }
+
+                #[test]
+                fn doubles() {
+                    assert_eq!(double(2), 4);
+                }
+            "#,
+        )
+        .file(
+            "bin/llvm-profdata",
+            "#!/bin/sh\necho \"llvm-profdata $*\"\n",
+        )
+        .file("bin/llvm-cov", "#!/bin/sh\necho \"llvm-cov $1\"\n")
+        .build();
+    let bin = p.root().join("bin");
+    for tool in ["llvm-profdata", "llvm-cov"].iter() {
+        std::fs::set_permissions(bin.join(tool), std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    p.cargo_wasi("coverage")
+        // The target doesn't ship a profiler runtime, so we build without it
+        // using an unstable flag.
+        .env("RUSTC_BOOTSTRAP", "1")
+        .env("LLVM_PROFDATA", bin.join("llvm-profdata"))
+        .env("LLVM_COV", bin.join("llvm-cov"))
+        .assert()
+        .stdout(predicate::str::contains("test doubles ... ok"))
+        .stdout(predicate::str::contains("llvm-profdata merge -sparse -o"))
+        .stdout(predicate::str::contains("llvm-cov show"))
+        .stdout(predicate::str::contains("llvm-cov report"))
+        .stderr(predicate::str::contains("Merging 1 coverage profiles"))
+        .success();
+
+    let output = p.root().join("target/wasi-coverage");
+    let profiles = std::fs::read_dir(output.join("profraw"))?.collect::<Vec<_>>();
+    assert_eq!(profiles.len(), 1);
+    let profile = std::fs::read(profiles[0].as_ref().unwrap().path())?;
+    assert_eq!(profile[..8], b"\x81Rforpl\xff"[..]);
+    assert_eq!(
+        std::fs::read_to_string(output.join("lcov.info"))?,
+        "llvm-cov export\n"
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn coverage_unsupported_llvm() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A toolchain newer than the raw profile format we know how to write.
+    let p = support::project()
+        .file("src/lib.rs", "")
+        .file(
+            "bin/rustc",
+            "#!/bin/sh\necho 'release: 1.99.0-nightly'\necho 'LLVM version: 99.1.0'\n",
+        )
+        .build();
+    let bin = p.root().join("bin");
+    std::fs::set_permissions(bin.join("rustc"), std::fs::Permissions::from_mode(0o755))?;
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![bin];
+    paths.extend(std::env::split_paths(&path));
+
+    p.cargo_wasi("coverage")
+        .env("PATH", std::env::join_paths(paths)?)
+        .assert()
+        .stderr(predicate::str::contains(
+            "only supports toolchains using LLVM 19 to 22, whose raw profile \
+             format it writes, but rustc 1.99.0-nightly uses LLVM 99.1.0",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn coverage_node_with_other_dirs() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Node preopens directories sorted by their guest path, so `.` comes
+    // before the one profiles are written to.
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                runtime = "node"
+                dirs = ["."]
+            "#,
+        )
+        .file(
+            "src/lib.rs",
+            r#"
+                pub fn double(x: u32) -> u32 {
+                    x * 2
+                }
+
+                #[test]
+                fn doubles() {
+                    assert_eq!(double(2), 4);
+                }
+            "#,
+        )
+        .file(
+            "bin/llvm-profdata",
+            "#!/bin/sh\necho \"llvm-profdata $*\"\n",
+        )
+        .file("bin/llvm-cov", "#!/bin/sh\necho \"llvm-cov $1\"\n")
+        .build();
+    if which::which("node").is_err() {
+        return Ok(());
+    }
+    let bin = p.root().join("bin");
+    for tool in ["llvm-profdata", "llvm-cov"].iter() {
+        std::fs::set_permissions(bin.join(tool), std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    p.cargo_wasi("coverage")
+        .env("RUSTC_BOOTSTRAP", "1")
+        .env("LLVM_PROFDATA", bin.join("llvm-profdata"))
+        .env("LLVM_COV", bin.join("llvm-cov"))
+        .assert()
+        .stderr(predicate::str::contains("Merging 1 coverage profiles"))
+        .success();
+
+    let profiles = p.root().join("target/wasi-coverage/profraw");
+    assert_eq!(std::fs::read_dir(profiles)?.count(), 1);
+    let stray = std::fs::read_dir(p.root())?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("profraw".as_ref()))
+        .count();
+    assert_eq!(stray, 0);
+    Ok(())
+}
+
+#[test]
+fn profile() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata]
+                wasm-name-section = false
+            "#,
+        )
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    println!("{}", fib(std::hint::black_box(32)));
+                }
+
+                #[inline(never)]
+                fn fib(n: u64) -> u64 {
+                    if n < 2 { n } else { fib(n - 1) + fib(n - 2) }
+                }
+            "#,
+        )
+        .build();
+
+    // The name section is kept for the profile, despite the configuration
+    p.cargo_wasi("profile --release --interval 100us")
+        .env("CARGO_TARGET_WASM32_WASIP1_RUNNER", "wasmtime")
+        .assert()
+        .stdout("2178309\n")
+        .stderr(predicate::str::contains("Profiled `foo.wasm` to `"))
+        .success();
+    let json = std::fs::read_to_string(p.root().join("target/wasi-profile/foo.json"))?;
+    assert!(json.contains("\"foo::fib"), "{}", json);
+
+    let output = p.root().join("foo.pb.gz");
+    p.cargo_wasi("profile --format pprof -o")
+        .arg(&output)
+        .env("CARGO_TARGET_WASM32_WASIP1_RUNNER", "wasmtime")
+        .assert()
+        .stdout("2178309\n")
+        .success();
+    assert!(std::fs::read(&output)?.starts_with(&[0x1f, 0x8b]));
+    assert!(!p.root().join("foo.json").exists());
+
+    p.cargo_wasi("profile --interval 0")
+        .assert()
+        .stderr(predicate::str::contains(
+            "`--interval` must be greater than zero",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn debug() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Stand-ins for the debuggers which print how they were launched.
+    let p = support::project()
+        .file("src/main.rs", "fn main() {}")
+        .file("bin/lldb", "#!/bin/sh\necho \"lldb: $*\"\n")
+        .file("bin/gdb", "#!/bin/sh\necho \"gdb: $*\"\n")
+        .build();
+    let bin = p.root().join("bin");
+    for debugger in ["lldb", "gdb"] {
+        std::fs::set_permissions(bin.join(debugger), std::fs::Permissions::from_mode(0o755))?;
+    }
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![bin];
+    paths.extend(std::env::split_paths(&path));
+    let path = std::env::join_paths(paths)?;
+
+    // Even the embedded runtime is swapped for `wasmtime` to debug with
+    p.cargo_wasi("debug -- arg")
+        .env("PATH", &path)
+        .assert()
+        .stdout(is_match(
+            "^lldb: -O settings set plugin.jit-loader.gdb.enable on -- \
+             wasmtime -D debug-info -O opt-level=0 -- \
+             target/wasm32-wasip1/debug/foo.wasm arg\n$",
+        )?)
+        .stderr(predicate::str::contains(
+            "Debugging `target/wasm32-wasip1/debug/foo.wasm` with `lldb`",
+        ))
+        .stderr(predicate::str::contains("no DWARF").not())
+        .success();
+
+    p.cargo_wasi("debug --debugger gdb --release")
+        .env("PATH", &path)
+        .assert()
+        .stdout(is_match(
+            "^gdb: --args wasmtime -D debug-info -O opt-level=0 -- \
+             target/wasm32-wasip1/release/foo.wasm\n$",
+        )?)
+        .stderr(predicate::str::contains(
+            "`target/wasm32-wasip1/release/foo.wasm` has no DWARF debuginfo",
+        ))
+        .success();
+
+    let output = p
+        .cargo_wasi("debug --vscode --debugger=gdb")
+        .env("PATH", &path)
+        .output()?;
+    assert!(output.status.success());
+    let launch = serde_json::from_slice::<serde_json::Value>(&output.stdout)?;
+    let configuration = &launch["configurations"][0];
+    assert_eq!(configuration["type"], "cppdbg");
+    assert_eq!(configuration["MIMode"], "gdb");
+    assert_eq!(configuration["name"], "Debug foo.wasm");
+    assert!(configuration["program"]
+        .as_str()
+        .unwrap()
+        .ends_with("wasmtime"));
+    assert_eq!(
+        configuration["args"],
+        serde_json::json!([
+            "-D",
+            "debug-info",
+            "-O",
+            "opt-level=0",
+            "--",
+            "target/wasm32-wasip1/debug/foo.wasm",
+        ]),
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn install() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A stand-in for the runtime which prints how the wrapper runs it.
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                dirs = ["."]
+                env = { GREETING = "it's me" }
+            "#,
+        )
+        .file("src/main.rs", "fn main() {}")
+        .file("bin/wasmtime", "#!/bin/sh\necho \"wasmtime: $*\"\n")
+        .build();
+    let bin = p.root().join("bin");
+    std::fs::set_permissions(bin.join("wasmtime"), std::fs::Permissions::from_mode(0o755))?;
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let mut paths = vec![bin];
+    paths.extend(std::env::split_paths(&path));
+    let path = std::env::join_paths(paths)?;
+
+    let installed = p.root().join("cargo-home/wasi/bin");
+    p.cargo_wasi("install --path .")
+        .env("PATH", &path)
+        .assert()
+        .stderr(predicate::str::contains(format!(
+            "Installing {}",
+            installed.join("foo.wasm").display()
+        )))
+        .stderr(predicate::str::contains(
+            "Installed package `foo v1.0.0` (executable `foo`)",
+        ))
+        .success();
+    assert!(installed.join("foo.wasm").exists());
+    let output = std::process::Command::new(installed.join("foo"))
+        .arg("an arg")
+        .env("PATH", &path)
+        .output()?;
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!(
+            "wasmtime: --dir .::. --env GREETING=it's me -- {} an arg\n",
+            installed.join("foo.wasm").display()
+        ),
+    );
+
+    p.cargo_wasi("install --list")
+        .assert()
+        .stdout("foo v1.0.0:\n    foo\n")
+        .success();
+    p.cargo_wasi("uninstall foo")
+        .assert()
+        .stderr(predicate::str::contains("Removed package `foo v1.0.0`"))
+        .success();
+    assert!(!installed.join("foo.wasm").exists());
+    assert!(!installed.join("foo").exists());
+    p.cargo_wasi("install --list").assert().stdout("").success();
+    p.cargo_wasi("uninstall foo")
+        .assert()
+        .stderr(predicate::str::contains("package `foo` is not installed"))
+        .failure();
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn own_options_after_non_utf8_args() -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let p = support::project().build();
+    // An argument that isn't UTF-8 isn't the end of our own options.
+    p.cargo_wasi("uninstall")
+        .arg(std::ffi::OsStr::from_bytes(b"foo\xff"))
+        .arg("--root")
+        .arg("elsewhere")
+        .assert()
+        .stderr(is_match(
+            "package `foo.*` is not installed in `.*elsewhere`",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn bench_compare() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [[bench]]
+                name = "speed"
+                harness = false
+            "#,
+        )
+        .file("src/lib.rs", "")
+        .file(
+            "benches/speed.rs",
+            r#"
+                fn main() {
+                    println!("test parse ... bench:       1,000 ns/iter (+/- 10)");
+                    println!("render                  time:   [2.0000 µs 2.0100 µs 2.0200 µs]");
+                }
+            "#,
+        )
+        .file(".gitignore", "target\ncargo-home\nwasm-cache\n")
+        .build();
+    let git = |args: &str| -> Result<()> {
+        let status = Command::new("git")
+            .args(["-c", "user.name=foo", "-c", "user.email=foo@example.com"])
+            .args(args.split_whitespace())
+            .current_dir(p.root())
+            .status()?;
+        assert!(status.success());
+        Ok(())
+    };
+    git("init -q")?;
+    git("add -A")?;
+    git("commit -q -m baseline")?;
+
+    p.cargo_wasi("bench")
+        .assert()
+        .stdout(predicate::str::contains("test parse ... bench:"))
+        .stderr(is_match(
+            r"Writing `.*[/\\]target[/\\]wasi-bench[/\\][0-9a-f]{40}\.json`",
+        )?)
+        .success();
+
+    std::fs::write(
+        p.root().join("benches/speed.rs"),
+        r#"
+            fn main() {
+                println!("test parse ... bench:       1,500 ns/iter (+/- 10)");
+                println!("render                  time:   [2.0000 µs 2.0100 µs 2.0200 µs]");
+                println!("test layout ... bench:         300 ns/iter (+/- 1)");
+            }
+        "#,
+    )?;
+    p.cargo_wasi("bench --compare HEAD")
+        .assert()
+        .stdout(is_match(
+            r"\nspeed::parse +1\.00 µs -> +1\.50 µs +\+50\.00%  regressed\n",
+        )?)
+        .stdout(is_match(
+            r"\nspeed::render +2\.01 µs -> +2\.01 µs +\+0\.00%  no change\n",
+        )?)
+        .stdout(is_match(r"\nspeed::layout +300\.00 ns  new\n$")?)
+        .stderr(is_match(r"wasi-bench[/\\][0-9a-f]{40}-dirty\.json")?)
+        .stderr(predicate::str::contains(
+            "Compared 1 regressed, 0 improved, 1 unchanged",
+        ))
+        .success();
+
+    p.cargo_wasi("bench --compare missing")
+        .assert()
+        .stderr(predicate::str::contains(
+            "no benchmark results for `missing`",
+        ))
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn test_reports() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.test-report]
+                junit = "reports/junit.xml"
+            "#,
+        )
+        .file(
+            "src/lib.rs",
+            r#"
+                #[test]
+                fn a_passes() {}
+
+                #[test]
+                #[ignore]
+                fn b_ignored() {}
+
+                #[test]
+                fn c_fails() {
+                    println!("about to fail");
+                    assert_eq!(1, 2);
+                }
+            "#,
+        )
+        .build();
+
+    // the tests run in one instance as usual, where libtest captures output
+    let json = p.root().join("report.json");
+    p.cargo_wasi("test --lib")
+        .env("CARGO_WASI_JSON_REPORT", &json)
+        .assert()
+        .stdout(is_match(
+            "running 3 tests\n\
+             test a_passes \\.\\.\\. ok\n\
+             test b_ignored \\.\\.\\. ignored\n\
+             test c_fails \\.\\.\\. ",
+        )?)
+        .stdout(predicate::str::contains("about to fail").not())
+        .failure();
+
+    let junit = std::fs::read_to_string(p.root().join("reports/junit.xml"))?;
+    assert!(is_match(
+        "(?s)<testsuite name=\"foo\" tests=\"3\" failures=\"1\" errors=\"0\" skipped=\"1\" .*\
+         <testcase classname=\"foo\" name=\"a_passes\" time=\"[\\d.]+\"/>\n \
+         +<testcase classname=\"foo\" name=\"b_ignored\" time=\"[\\d.]+\">\n +<skipped/>.*\
+         <testcase classname=\"foo\" name=\"c_fails\" time=\"[\\d.]+\">\n \
+         +<failure message=\"[^\"]*\">.*wasm trap.*</failure>\n \
+         +<system-out>.*test c_fails \\.\\.\\. </system-out>"
+    )?
+    .eval(&junit));
+
+    let json = std::fs::read_to_string(&json)?;
+    let events = json
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<Vec<serde_json::Value>, _>>()?;
+    assert_eq!(events.len(), 8);
+    assert_eq!(events[0]["event"], "started");
+    assert_eq!(events[0]["test_count"], 3);
+    assert_eq!(events[2]["name"], "a_passes");
+    assert_eq!(events[2]["event"], "ok");
+    assert_eq!(events[4]["event"], "ignored");
+    assert_eq!(events[6]["name"], "c_fails");
+    assert_eq!(events[6]["event"], "failed");
+    assert_eq!(events[6]["binary"], "foo");
+    assert!(events[6]["trap"].as_str().unwrap().contains("wasm trap"));
+    assert!(events[6]["exec_time"].is_f64());
+    assert_eq!(events[7]["event"], "failed");
+    assert_eq!(events[7]["ignored"], 1);
+
+    // other formats can't be read, unless each test has its own instance
+    p.cargo_wasi("test --lib -- --format terse")
+        .assert()
+        .stderr(predicate::str::contains("`--isolate-tests`"))
+        .code(1);
+    p.cargo_wasi("test --lib --isolate-tests -- --format terse")
+        .assert()
+        .stdout(is_match("test c_fails \\.\\.\\. FAILED\n")?)
+        .code(101);
+    let junit = std::fs::read_to_string(p.root().join("reports/junit.xml"))?;
+    assert!(is_match("(?s)<system-out>.*about to fail.*</system-out>")?.eval(&junit));
+    Ok(())
+}
+
+#[test]
+fn runner_limits() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                timeout = 1
+                max-memory = 16777216
+            "#,
+        )
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    match std::env::args().nth(1).as_deref() {
+                        Some("spin") => loop {
+                            std::hint::black_box(0);
+                        },
+                        Some("alloc") => drop(vec![1u8; 64 << 20]),
+                        _ => println!("ok"),
+                    }
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasi("run").assert().stdout("ok\n").success();
+    p.cargo_wasi("run -- spin")
+        .assert()
+        .stderr(is_match(
+            "error: `.*foo\\.wasm` exceeded its timeout of 1s\n$",
+        )?)
+        .code(1);
+    p.cargo_wasi("run -- alloc")
+        .assert()
+        .stderr(is_match(
+            "error: `.*foo\\.wasm` exceeded its memory limit of 16777216 bytes\n$",
+        )?)
+        .code(1);
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "embedded-runtime")]
+fn embedded_runtime() -> Result<()> {
+    let p = support::project()
+        .file(
+            "Cargo.toml",
+            r#"
+                [package]
+                name = "foo"
+                version = "1.0.0"
+
+                [package.metadata.wasi.runner]
+                dirs = ["fixtures"]
+                env = { FOO = "bar" }
+            "#,
+        )
+        .file("fixtures/a.txt", "hello")
+        .file(
+            "src/main.rs",
+            r#"
+                fn main() {
+                    println!("{}", std::fs::read_to_string("fixtures/a.txt").unwrap());
+                    println!("{}", std::env::var("FOO").unwrap());
+                    match std::env::args().nth(1).as_deref() {
+                        Some("exit") => std::process::exit(3),
+                        Some("trap") => trap_here(),
+                        _ => {}
+                    }
+                }
+
+                #[inline(never)]
+                fn trap_here() {
+                    core::arch::wasm32::unreachable()
+                }
+            "#,
+        )
+        .build();
+
+    p.cargo_wasi("run")
+        .assert()
+        .stdout("hello\nbar\n")
+        .success();
+    p.cargo_wasi("run exit")
+        .assert()
+        .stdout("hello\nbar\n")
+        .code(3);
+    p.cargo_wasi("run trap")
+        .assert()
+        .stderr(is_match(
+            "failed to run `.*foo.wasm`(.|\n)*wasm backtrace(.|\n)*\
+             wasm trap: wasm `unreachable` instruction executed",
+        )?)
+        .code(1);
+    Ok(())
+}
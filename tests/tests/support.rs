@@ -98,16 +98,17 @@ impl Project {
     }
 
     pub fn debug_wasm(&self, name: &str) -> PathBuf {
-        self.build_dir()
-            .join("wasm32-wasi")
-            .join("debug")
-            .join(format!("{}.wasm", name))
+        self.target_wasm("wasm32-wasip1", "debug", name)
     }
 
     pub fn release_wasm(&self, name: &str) -> PathBuf {
+        self.target_wasm("wasm32-wasip1", "release", name)
+    }
+
+    pub fn target_wasm(&self, target: &str, profile: &str, name: &str) -> PathBuf {
         self.build_dir()
-            .join("wasm32-wasi")
-            .join("release")
+            .join(target)
+            .join(profile)
             .join(format!("{}.wasm", name))
     }
 
@@ -115,10 +116,11 @@ impl Project {
         let mut process = super::cargo_wasi(cmd);
         process
             .current_dir(&self.root)
-            .env("CARGO_HOME", self.root.join("cargo-home"));
+            .env("CARGO_HOME", self.root.join("cargo-home"))
+            .env("CARGO_WASI_WASM_CACHE_DIR", self.root.join("wasm-cache"));
 
         if let Some(runtime_override) = &self.runtime_override {
-            process.env("CARGO_TARGET_WASM32_WASI_RUNNER", runtime_override);
+            process.env("CARGO_TARGET_WASM32_WASIP1_RUNNER", runtime_override);
         }
 
         return process;